@@ -0,0 +1,193 @@
+use crate::error::MorphaError;
+use rusqlite::Connection;
+use std::io::Read;
+use std::path::Path;
+
+/// Most bytes of a single attachment
+pub const ATTACHMENT_MAX_BYTES: usize = 256 * 1024;
+
+/// Most bytes of all the attachments sent with one prompt
+pub const ATTACHMENTS_MAX_BYTES: usize = 512 * 1024;
+
+/// Name of the attachment read from standard input
+pub const STDIN_NAME: &str = "stdin";
+
+/// Introduction placed before attachments so the assistant knows how to treat them
+const ATTACHMENT_PREAMBLE: &str = "The following documents are attached to my request. \
+Each appears between <attachment> tags with its name. Use them to answer my request after the \
+attachments.";
+
+/// A document sent along with a prompt
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attachment {
+    /// File path, or `stdin` for piped input
+    pub name: String,
+    pub text: String,
+}
+
+impl Attachment {
+    /// An attachment of `text`, if it is within the size limit
+    pub fn new(name: &str, text: String) -> Result<Self, MorphaError> {
+        if text.len() > ATTACHMENT_MAX_BYTES {
+            return Err(too_large(name, text.len(), ATTACHMENT_MAX_BYTES));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            text,
+        })
+    }
+
+    /// An attachment of a text file, named by its path
+    pub fn read(path: &Path) -> Result<Self, MorphaError> {
+        let name = path.to_string_lossy();
+        let bytes = std::fs::metadata(path)
+            .map_err(|e| MorphaError::Attachment(format!("{}: {}", name, e)))?
+            .len() as usize;
+        // check before reading so a huge file is never loaded
+        if bytes > ATTACHMENT_MAX_BYTES {
+            return Err(too_large(&name, bytes, ATTACHMENT_MAX_BYTES));
+        }
+        // a pipe or device reports no size, so the read is limited too
+        Self::read_from(&name, std::fs::File::open(path)?)
+    }
+
+    /// An attachment of text from `reader`, such as standard input, reading no more than one
+    /// byte past the size limit
+    pub fn read_from(name: &str, reader: impl Read) -> Result<Self, MorphaError> {
+        let mut bytes = Vec::new();
+        reader
+            .take(ATTACHMENT_MAX_BYTES as u64 + 1)
+            .read_to_end(&mut bytes)?;
+        if bytes.len() > ATTACHMENT_MAX_BYTES {
+            return Err(MorphaError::Attachment(format!(
+                "{}: more than the limit of {} bytes",
+                name, ATTACHMENT_MAX_BYTES
+            )));
+        }
+        let text = String::from_utf8(bytes)
+            .map_err(|_| MorphaError::Attachment(format!("{}: not a text file", name)))?;
+        Self::new(name, text)
+    }
+}
+
+fn too_large(name: &str, bytes: usize, limit: usize) -> MorphaError {
+    MorphaError::Attachment(format!(
+        "{}: {} bytes is more than the limit of {}",
+        name, bytes, limit
+    ))
+}
+
+/// Check that the attachments of one prompt are within the size limit together
+pub fn check_total(attachments: &[Attachment]) -> Result<(), MorphaError> {
+    let bytes: usize = attachments.iter().map(|a| a.text.len()).sum();
+    if bytes > ATTACHMENTS_MAX_BYTES {
+        return Err(too_large("all files", bytes, ATTACHMENTS_MAX_BYTES));
+    }
+    Ok(())
+}
+
+/// Format an attachment between delimiters the assistant can reliably identify
+pub fn format_attachment(attachment: &Attachment) -> String {
+    format!(
+        "<attachment name=\"{}\">\n{}\n</attachment>",
+        attachment.name.replace('"', "&quot;"),
+        attachment.text.trim_end(),
+    )
+}
+
+/// Build the text sent to the assistant for `prompt` with documents attached
+pub fn attach(prompt: &str, attachments: &[Attachment]) -> String {
+    if attachments.is_empty() {
+        return prompt.to_string();
+    }
+    let mut text = format!("{}\n\n", ATTACHMENT_PREAMBLE);
+    for attachment in attachments {
+        text.push_str(&format_attachment(attachment));
+        text.push_str("\n\n");
+    }
+    text.push_str(prompt);
+    text
+}
+
+/// Record in the archive that `message_id` was sent with the attachments
+pub fn write_to_database(
+    db: &Connection,
+    message_id: i64,
+    attachments: &[Attachment],
+) -> rusqlite::Result<()> {
+    for attachment in attachments {
+        db.execute(
+            "INSERT INTO attachments (message_id, name, bytes) VALUES (?1, ?2, ?3)",
+            rusqlite::params![message_id, attachment.name, attachment.text.len()],
+        )?;
+    }
+    Ok(())
+}
+
+/// Names and sizes of the attachments sent with `message_id`
+pub fn attached_to(db: &Connection, message_id: i64) -> rusqlite::Result<Vec<(String, i64)>> {
+    let mut stmt =
+        db.prepare("SELECT name, bytes FROM attachments WHERE message_id = ?1 ORDER BY rowid")?;
+    let rows = stmt.query_map([message_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attach() {
+        assert_eq!(attach("plain prompt", &[]), "plain prompt");
+
+        let diff = Attachment::new(STDIN_NAME, "-old\n+new\n".to_string()).unwrap();
+        let text = attach("Review this.", &[diff]);
+        assert!(text.starts_with(ATTACHMENT_PREAMBLE));
+        assert!(text.ends_with(
+            "\n\n<attachment name=\"stdin\">\n-old\n+new\n</attachment>\n\nReview this."
+        ));
+    }
+
+    #[test]
+    fn test_limits() {
+        let text = "x".repeat(ATTACHMENT_MAX_BYTES + 1);
+        let error = Attachment::new("big.txt", text).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "cannot attach big.txt: {} bytes is more than the limit of {}",
+                ATTACHMENT_MAX_BYTES + 1,
+                ATTACHMENT_MAX_BYTES
+            )
+        );
+
+        let text = "x".repeat(ATTACHMENT_MAX_BYTES);
+        let attachments = vec![Attachment::new("a", text).unwrap(); 2];
+        assert!(check_total(&attachments).is_ok());
+        let attachments = vec![attachments[0].clone(); 3];
+        assert!(check_total(&attachments).is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        let dir = std::env::temp_dir().join(format!("morpha-attachment-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("notes.md"), "Remember this.\n").unwrap();
+        std::fs::write(dir.join("image.png"), [0x89, 0x50, 0xff, 0xfe]).unwrap();
+
+        let attachment = Attachment::read(&dir.join("notes.md")).unwrap();
+        assert!(attachment.name.ends_with("notes.md"));
+        assert_eq!(attachment.text, "Remember this.\n");
+        let error = Attachment::read(&dir.join("image.png")).unwrap_err();
+        assert!(error.to_string().ends_with("image.png: not a text file"));
+        assert!(Attachment::read(&dir.join("missing.md")).is_err());
+
+        // input of unknown size stops being read past the limit
+        let endless = std::io::repeat(b'x');
+        let error = Attachment::read_from(STDIN_NAME, endless).unwrap_err();
+        assert!(error
+            .to_string()
+            .ends_with("stdin: more than the limit of 262144 bytes"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
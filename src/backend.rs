@@ -0,0 +1,205 @@
+use crate::conversation::Message;
+use crate::error::MorphaError;
+use crate::personality::Personality;
+
+use async_openai::{config::OpenAIConfig, Client};
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use std::future::Future;
+use std::time::Duration;
+
+pub mod assistants;
+pub mod chat;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+
+/// API used to obtain responses
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    /// Assistants API, keeping history in a remote assistant and thread
+    Assistants,
+    /// Chat Completions API, sending the message history with each request
+    Chat,
+}
+
+/// Limits on waiting for requests and retrying those that fail
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Policy {
+    /// Longest wait for a response, or for the next part of a streamed response
+    pub timeout: Duration,
+    /// Attempts after the first for requests failing with transient errors
+    pub retries: u32,
+    /// Delay before the first retry, doubling for each one after
+    pub backoff: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(120),
+            retries: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Policy {
+    /// Await `request`, failing with `MorphaError::Timeout` if it takes longer than the timeout
+    pub async fn limit<T, E>(
+        &self,
+        request: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, MorphaError>
+    where
+        MorphaError: From<E>,
+    {
+        match tokio::time::timeout(self.timeout, request).await {
+            Ok(result) => Ok(result?),
+            Err(_) => Err(MorphaError::Timeout(self.timeout)),
+        }
+    }
+
+    /// Await the next part of a streamed response, failing if it takes longer than the timeout
+    pub async fn next<S: Stream + Unpin>(
+        &self,
+        stream: &mut S,
+    ) -> Result<Option<S::Item>, MorphaError> {
+        tokio::time::timeout(self.timeout, stream.next())
+            .await
+            .map_err(|_| MorphaError::Timeout(self.timeout))
+    }
+
+    /// Delay before repeating a request after `attempt` retries failed with `error`,
+    /// or `None` if it should not be repeated
+    pub fn retry_delay(&self, attempt: u32, error: &MorphaError) -> Option<Duration> {
+        if attempt >= self.retries || !error.is_transient() {
+            return None;
+        }
+        Some(self.backoff * 2u32.saturating_pow(attempt))
+    }
+}
+
+/// Receiver of response text as it streams in
+pub type OnText<'a> = &'a mut dyn FnMut(&str) -> std::io::Result<()>;
+
+/// A response to a prompt with details for archiving
+pub struct Reply {
+    pub text: String,
+    pub model: String,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    /// Identifier of the remote run or completion producing the response
+    pub run_id: Option<String>,
+    /// Final status of the run, or the reason a completion finished
+    pub status: String,
+}
+
+/// A conversational API the session exchanges messages with
+#[async_trait(?Send)]
+pub trait Backend {
+    /// Prepare a session with the personality's instructions, replaying `history`
+    async fn start(
+        &mut self,
+        personality: &Personality,
+        history: &[Message],
+    ) -> Result<(), MorphaError>;
+
+    /// Answer the prompts that follow with another personality's instructions, model and temperature
+    async fn set_personality(&mut self, personality: &Personality) -> Result<(), MorphaError>;
+
+    /// Replace the conversation history, as when resuming an archived conversation
+    async fn load_history(&mut self, history: &[Message]) -> Result<(), MorphaError>;
+
+    /// Send a prompt, passing text to `on_text` as it is generated, and return the complete response
+    async fn send(&mut self, prompt: &str, on_text: OnText<'_>) -> Result<Reply, MorphaError>;
+
+    /// Abandon the response to a prompt whose `send` was interrupted
+    async fn cancel(&mut self) -> Result<(), MorphaError> {
+        Ok(())
+    }
+
+    /// Release any remote resources held for the session
+    async fn close(&mut self) -> Result<(), MorphaError>;
+}
+
+/// Create a client for the OpenAI API, or for an OpenAI-compatible server at `api_base`
+pub fn client(api_base: Option<&str>, policy: &Policy) -> Client<OpenAIConfig> {
+    let mut config = OpenAIConfig::new();
+    if let Some(api_base) = api_base {
+        config = config.with_api_base(api_base.trim_end_matches('/'));
+    }
+    // the client retries rate limited requests itself, except those streaming a response
+    let backoff = backoff::ExponentialBackoffBuilder::new()
+        .with_initial_interval(policy.backoff)
+        .with_multiplier(2.0)
+        .with_max_elapsed_time(Some(policy.timeout))
+        .build();
+    Client::with_config(config).with_backoff(backoff)
+}
+
+/// Identifiers of the models offered by the server, sorted by name
+pub async fn list_models(client: &Client<OpenAIConfig>) -> Result<Vec<String>, MorphaError> {
+    let mut ids: Vec<String> = client
+        .models()
+        .list()
+        .await?
+        .data
+        .into_iter()
+        .map(|m| m.id)
+        .collect();
+    ids.sort();
+    Ok(ids)
+}
+
+/// Create a backend of the given kind using `model` for responses
+pub fn new(
+    kind: Kind,
+    client: Client<OpenAIConfig>,
+    model: &str,
+    policy: Policy,
+) -> Box<dyn Backend> {
+    match kind {
+        Kind::Assistants => Box::new(assistants::Assistants::new(client, model, policy)),
+        Kind::Chat => Box::new(chat::Chat::new(client, model, policy)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_retry_delay() {
+        let policy = Policy::default();
+        let timeout = MorphaError::Timeout(policy.timeout);
+        assert_eq!(
+            policy.retry_delay(0, &timeout),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.retry_delay(2, &timeout),
+            Some(Duration::from_secs(4))
+        );
+        assert_eq!(policy.retry_delay(3, &timeout), None);
+        assert_eq!(policy.retry_delay(0, &MorphaError::NotStarted), None);
+    }
+
+    #[test]
+    fn test_policy_limit() {
+        let policy = Policy {
+            timeout: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(policy.limit(async {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            Ok::<_, MorphaError>(())
+        }));
+        assert!(matches!(result, Err(MorphaError::Timeout(_))));
+        let result = runtime.block_on(policy.limit(async { Ok::<_, MorphaError>(1) }));
+        assert_eq!(result.unwrap(), 1);
+    }
+}
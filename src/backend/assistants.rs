@@ -0,0 +1,361 @@
+use crate::backend::{Backend, OnText, Policy, Reply};
+use crate::conversation::Message;
+use crate::error::MorphaError;
+use crate::personality::Personality;
+
+use async_openai::{
+    config::OpenAIConfig,
+    error::{ApiError, OpenAIError},
+    types::{
+        AssistantStreamEvent, CreateAssistantRequestArgs, CreateMessageRequestArgs,
+        CreateRunRequestArgs, CreateThreadRequest, CreateThreadRequestArgs, LastErrorCode,
+        MessageDeltaContent, MessageRole, ModifyAssistantRequestArgs, RunObject, RunStatus,
+    },
+    Client,
+};
+use async_trait::async_trait;
+use std::time::Duration;
+
+/// Interval between checks on the status of a run being cancelled
+const POLL_INTERVAL_MSEC: u64 = 500;
+
+/// Sampling temperature the API uses unless told otherwise
+const TEMPERATURE_DEFAULT: f32 = 1.0;
+
+/// Backend using a remote assistant and thread from the Assistants API
+pub struct Assistants {
+    client: Client<OpenAIConfig>,
+    model: String,
+    policy: Policy,
+    assistant_id: Option<String>,
+    thread_id: Option<String>,
+    /// Identifier of the run in progress
+    run_id: Option<String>,
+}
+
+impl Assistants {
+    pub fn new(client: Client<OpenAIConfig>, model: &str, policy: Policy) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            policy,
+            assistant_id: None,
+            thread_id: None,
+            run_id: None,
+        }
+    }
+
+    /// Create a run of the thread and stream its response
+    async fn stream_run(
+        &mut self,
+        thread_id: &str,
+        assistant_id: &str,
+        on_text: OnText<'_>,
+    ) -> Result<Reply, MorphaError> {
+        let run_request = CreateRunRequestArgs::default()
+            .assistant_id(assistant_id)
+            .build()?;
+        let mut stream = self
+            .policy
+            .limit(
+                self.client
+                    .threads()
+                    .runs(thread_id)
+                    .create_stream(run_request),
+            )
+            .await?;
+
+        let mut text = String::new();
+        while let Some(event) = self.policy.next(&mut stream).await? {
+            match event? {
+                AssistantStreamEvent::ThreadRunCreated(run) => self.run_id = Some(run.id),
+                AssistantStreamEvent::ThreadMessageDelta(message) => {
+                    for content in message.delta.content.unwrap_or_default() {
+                        match content {
+                            MessageDeltaContent::Text(delta) => {
+                                if let Some(value) = delta.text.and_then(|t| t.value) {
+                                    on_text(&value)?;
+                                    text.push_str(&value);
+                                }
+                            }
+                            MessageDeltaContent::ImageFile(_)
+                            | MessageDeltaContent::ImageUrl(_) => {
+                                return Err(MorphaError::Unsupported("images"));
+                            }
+                            MessageDeltaContent::Refusal(refusal) => {
+                                return Err(MorphaError::Refusal(
+                                    refusal.refusal.unwrap_or_default(),
+                                ));
+                            }
+                        }
+                    }
+                }
+                AssistantStreamEvent::ThreadRunCompleted(run)
+                | AssistantStreamEvent::ThreadRunIncomplete(run) => {
+                    return Ok(reply(text, run));
+                }
+                AssistantStreamEvent::ThreadRunFailed(run) => return Err(run_failed(run)),
+                AssistantStreamEvent::ThreadRunCancelled(run)
+                | AssistantStreamEvent::ThreadRunExpired(run)
+                | AssistantStreamEvent::ThreadRunRequiresAction(run) => {
+                    return Err(MorphaError::Run {
+                        status: run_status_name(&run.status),
+                        reason: None,
+                    });
+                }
+                AssistantStreamEvent::ErrorEvent(e) => {
+                    return Err(OpenAIError::ApiError(e).into());
+                }
+                AssistantStreamEvent::Done(_) => break,
+                _ => {}
+            }
+        }
+        Err(MorphaError::StreamEnded)
+    }
+
+    /// Identifier of the thread, which exists once the session has started
+    fn thread_id(&self) -> Result<&str, MorphaError> {
+        self.thread_id.as_deref().ok_or(MorphaError::NotStarted)
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Assistants {
+    async fn start(
+        &mut self,
+        personality: &Personality,
+        history: &[Message],
+    ) -> Result<(), MorphaError> {
+        let mut assistant_request = CreateAssistantRequestArgs::default();
+        assistant_request
+            .name(&personality.name)
+            .instructions(&personality.instructions)
+            .model(personality.model.as_deref().unwrap_or(&self.model));
+        if let Some(temperature) = personality.temperature {
+            assistant_request.temperature(temperature);
+        }
+        let assistant = self
+            .client
+            .assistants()
+            .create(assistant_request.build()?)
+            .await?;
+        self.assistant_id = Some(assistant.id);
+        self.load_history(history).await
+    }
+
+    async fn set_personality(&mut self, personality: &Personality) -> Result<(), MorphaError> {
+        let assistant_id = self
+            .assistant_id
+            .as_deref()
+            .ok_or(MorphaError::NotStarted)?;
+        // an unset temperature cannot be removed from the assistant, so restore the default
+        let request = ModifyAssistantRequestArgs::default()
+            .name(&personality.name)
+            .instructions(&personality.instructions)
+            .model(personality.model.as_deref().unwrap_or(&self.model))
+            .temperature(personality.temperature.unwrap_or(TEMPERATURE_DEFAULT))
+            .build()?;
+        let assistants = self.client.assistants();
+        self.policy
+            .limit(assistants.update(assistant_id, request))
+            .await?;
+        Ok(())
+    }
+
+    async fn load_history(&mut self, history: &[Message]) -> Result<(), MorphaError> {
+        if let Some(thread_id) = self.thread_id.take() {
+            self.client.threads().delete(&thread_id).await?;
+        }
+        let thread = self
+            .client
+            .threads()
+            .create(thread_request(history)?)
+            .await?;
+        self.thread_id = Some(thread.id);
+        Ok(())
+    }
+
+    async fn send(&mut self, prompt: &str, on_text: OnText<'_>) -> Result<Reply, MorphaError> {
+        let thread_id = self.thread_id()?.to_string();
+        let assistant_id = self.assistant_id.clone().ok_or(MorphaError::NotStarted)?;
+
+        //create a message for the thread
+        let message = CreateMessageRequestArgs::default()
+            .content(prompt.to_string())
+            .build()?;
+
+        //attach message to the thread
+        let _message_obj = self
+            .policy
+            .limit(self.client.threads().messages(&thread_id).create(message))
+            .await?;
+
+        //run the thread, starting a new run if one fails before any text arrives
+        let mut attempt = 0;
+        loop {
+            let mut streamed = false;
+            let result = self
+                .stream_run(&thread_id, &assistant_id, &mut |text| {
+                    streamed = true;
+                    on_text(text)
+                })
+                .await;
+            self.run_id = None;
+            match result {
+                Err(e) if !streamed => match self.policy.retry_delay(attempt, &e) {
+                    Some(delay) => {
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => return Err(e),
+                },
+                result => return result,
+            }
+        }
+    }
+
+    async fn cancel(&mut self) -> Result<(), MorphaError> {
+        let (Some(run_id), Some(thread_id)) = (self.run_id.take(), self.thread_id.clone()) else {
+            return Ok(());
+        };
+        let threads = self.client.threads();
+        let runs = threads.runs(&thread_id);
+        self.policy.limit(runs.cancel(&run_id)).await?;
+
+        // the thread accepts no new messages until the run has stopped
+        self.policy
+            .limit(async {
+                loop {
+                    let run = runs.retrieve(&run_id).await?;
+                    match run.status {
+                        RunStatus::Queued
+                        | RunStatus::InProgress
+                        | RunStatus::Cancelling
+                        | RunStatus::RequiresAction => {}
+                        _ => return Ok::<_, MorphaError>(()),
+                    }
+                    tokio::time::sleep(Duration::from_millis(POLL_INTERVAL_MSEC)).await;
+                }
+            })
+            .await
+    }
+
+    async fn close(&mut self) -> Result<(), MorphaError> {
+        // remove assistant and threads, attempting both before reporting a failure
+        let mut result = Ok(());
+        if let Some(assistant_id) = self.assistant_id.take() {
+            if let Err(e) = self.client.assistants().delete(&assistant_id).await {
+                result = Err(e.into());
+            }
+        }
+        if let Some(thread_id) = self.thread_id.take() {
+            if let Err(e) = self.client.threads().delete(&thread_id).await {
+                result = Err(e.into());
+            }
+        }
+        result
+    }
+}
+
+/// Error for a failed run, as an API error when the failure is worth retrying
+fn run_failed(run: RunObject) -> MorphaError {
+    let Some(error) = run.last_error else {
+        return MorphaError::Run {
+            status: run_status_name(&run.status),
+            reason: None,
+        };
+    };
+    let (code, r#type) = match error.code {
+        LastErrorCode::RateLimitExceeded => ("rate_limit_exceeded", "requests"),
+        LastErrorCode::ServerError => ("server_error", "server_error"),
+        LastErrorCode::InvalidPrompt => {
+            return MorphaError::Run {
+                status: run_status_name(&run.status),
+                reason: Some(error.message),
+            }
+        }
+    };
+    MorphaError::Api(OpenAIError::ApiError(ApiError {
+        message: error.message,
+        r#type: Some(r#type.to_string()),
+        param: None,
+        code: Some(code.to_string()),
+    }))
+}
+
+/// Reply holding the streamed `text` and the details of the finished run
+fn reply(text: String, run: RunObject) -> Reply {
+    Reply {
+        text,
+        model: run.model,
+        prompt_tokens: run.usage.as_ref().map(|u| u.prompt_tokens as i64),
+        completion_tokens: run.usage.as_ref().map(|u| u.completion_tokens as i64),
+        status: run_status_name(&run.status).to_string(),
+        run_id: Some(run.id),
+    }
+}
+
+/// Name of a run status as used by the OpenAI API
+pub fn run_status_name(status: &RunStatus) -> &'static str {
+    match status {
+        RunStatus::Queued => "queued",
+        RunStatus::InProgress => "in_progress",
+        RunStatus::RequiresAction => "requires_action",
+        RunStatus::Cancelling => "cancelling",
+        RunStatus::Cancelled => "cancelled",
+        RunStatus::Failed => "failed",
+        RunStatus::Completed => "completed",
+        RunStatus::Incomplete => "incomplete",
+        RunStatus::Expired => "expired",
+    }
+}
+
+/// Build a thread request replaying archived messages as history
+pub fn thread_request(history: &[Message]) -> Result<CreateThreadRequest, OpenAIError> {
+    let mut messages = Vec::new();
+    for message in history {
+        messages.push(
+            CreateMessageRequestArgs::default()
+                .role(MessageRole::User)
+                .content(message.prompt.clone())
+                .build()?,
+        );
+        messages.push(
+            CreateMessageRequestArgs::default()
+                .role(MessageRole::Assistant)
+                .content(message.response.clone())
+                .build()?,
+        );
+    }
+    let mut request = CreateThreadRequestArgs::default();
+    if !messages.is_empty() {
+        request.messages(messages);
+    }
+    request.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_thread_request() {
+        let history = [Message {
+            prompt: "Why?".to_string(),
+            response: "Because.".to_string(),
+            ..Default::default()
+        }];
+        let request = serde_json::to_value(thread_request(&history).unwrap()).unwrap();
+        assert_eq!(
+            request["messages"],
+            json!([
+                {"role": "user", "content": "Why?", "attachments": null},
+                {"role": "assistant", "content": "Because.", "attachments": null},
+            ])
+        );
+        // a new conversation starts an empty thread
+        let request = serde_json::to_value(thread_request(&[]).unwrap()).unwrap();
+        assert_eq!(request.get("messages"), None);
+    }
+}
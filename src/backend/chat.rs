@@ -0,0 +1,318 @@
+use crate::backend::{Backend, OnText, Policy, Reply};
+use crate::conversation::Message;
+use crate::error::MorphaError;
+use crate::personality::Personality;
+
+use async_openai::{
+    config::OpenAIConfig,
+    types::{
+        ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs,
+        ChatCompletionStreamOptions, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
+        FinishReason,
+    },
+    Client,
+};
+use async_trait::async_trait;
+
+/// Backend sending the full message history to the Chat Completions API with each prompt
+pub struct Chat {
+    client: Client<OpenAIConfig>,
+    model: String,
+    policy: Policy,
+    /// Model of the personality, replacing `model`
+    personality_model: Option<String>,
+    temperature: Option<f32>,
+    instructions: Option<ChatCompletionRequestMessage>,
+    history: Vec<ChatCompletionRequestMessage>,
+    /// Whether the last prompt in the history is still awaiting its response
+    pending: bool,
+    /// Whether to ask for token usage, which some compatible servers reject
+    include_usage: bool,
+}
+
+impl Chat {
+    pub fn new(client: Client<OpenAIConfig>, model: &str, policy: Policy) -> Self {
+        Self {
+            client,
+            model: model.to_string(),
+            policy,
+            personality_model: None,
+            temperature: None,
+            instructions: None,
+            history: Vec::new(),
+            pending: false,
+            include_usage: true,
+        }
+    }
+
+    /// Model answering prompts
+    fn model(&self) -> &str {
+        self.personality_model.as_deref().unwrap_or(&self.model)
+    }
+
+    /// Every message of the conversation, beginning with the instructions
+    fn messages(&self) -> Vec<ChatCompletionRequestMessage> {
+        self.instructions
+            .iter()
+            .chain(self.history.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// Request for the completion of the conversation so far
+    fn request(&self) -> Result<CreateChatCompletionRequest, MorphaError> {
+        let mut request = CreateChatCompletionRequestArgs::default();
+        request.model(self.model()).messages(self.messages());
+        if self.include_usage {
+            request.stream_options(ChatCompletionStreamOptions {
+                include_usage: true,
+            });
+        }
+        if let Some(temperature) = self.temperature {
+            request.temperature(temperature);
+        }
+        Ok(request.build()?)
+    }
+
+    /// Stream the completion of `request`, collecting the response as it arrives
+    ///
+    /// A stream ending before a choice finishes, as when the connection drops, is an error so the
+    /// partial response is not taken for a complete one.
+    async fn receive(
+        &self,
+        request: CreateChatCompletionRequest,
+        on_text: OnText<'_>,
+    ) -> Result<Reply, MorphaError> {
+        let mut stream = self
+            .policy
+            .limit(self.client.chat().create_stream(request))
+            .await?;
+        let mut reply = Reply {
+            text: String::new(),
+            model: self.model().to_string(),
+            prompt_tokens: None,
+            completion_tokens: None,
+            run_id: None,
+            status: String::new(),
+        };
+        let mut finished = false;
+        while let Some(chunk) = self.policy.next(&mut stream).await? {
+            let chunk = chunk?;
+            reply.model = chunk.model;
+            reply.run_id = Some(chunk.id);
+            // only the final chunk carries usage
+            if let Some(usage) = chunk.usage {
+                reply.prompt_tokens = Some(usage.prompt_tokens as i64);
+                reply.completion_tokens = Some(usage.completion_tokens as i64);
+            }
+            for choice in chunk.choices {
+                if let Some(text) = choice.delta.content {
+                    on_text(&text)?;
+                    reply.text.push_str(&text);
+                }
+                if let Some(reason) = choice.finish_reason {
+                    reply.status = finish_reason_name(&reason).to_string();
+                    finished = true;
+                }
+            }
+        }
+        match finished {
+            true => Ok(reply),
+            false => Err(MorphaError::StreamEnded),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Chat {
+    async fn start(
+        &mut self,
+        personality: &Personality,
+        history: &[Message],
+    ) -> Result<(), MorphaError> {
+        self.set_personality(personality).await?;
+        self.load_history(history).await
+    }
+
+    async fn set_personality(&mut self, personality: &Personality) -> Result<(), MorphaError> {
+        self.instructions = Some(
+            ChatCompletionRequestSystemMessageArgs::default()
+                .content(personality.instructions.clone())
+                .build()?
+                .into(),
+        );
+        self.personality_model = personality.model.clone();
+        self.temperature = personality.temperature;
+        Ok(())
+    }
+
+    async fn load_history(&mut self, history: &[Message]) -> Result<(), MorphaError> {
+        self.history.clear();
+        for message in history {
+            self.history.push(user_message(&message.prompt)?);
+            self.history.push(assistant_message(&message.response)?);
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, prompt: &str, on_text: OnText<'_>) -> Result<Reply, MorphaError> {
+        self.history.push(user_message(prompt)?);
+        let mut request = self.request()?;
+        self.pending = true;
+        let mut attempt = 0;
+        let result = loop {
+            let mut streamed = false;
+            let result = self
+                .receive(request.clone(), &mut |text| {
+                    streamed = true;
+                    on_text(text)
+                })
+                .await;
+            // text already printed cannot be taken back, so only retry before any arrives
+            match result {
+                // servers that do not know stream options reject them, so try once without
+                Err(e) if !streamed && self.include_usage && e.is_bad_request() => {
+                    self.include_usage = false;
+                    match self.request() {
+                        Ok(without) => request = without,
+                        Err(e) => break Err(e),
+                    }
+                }
+                Err(e) if !streamed => match self.policy.retry_delay(attempt, &e) {
+                    Some(delay) => {
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => break Err(e),
+                },
+                result => break result,
+            }
+        };
+        self.pending = false;
+        match result {
+            Ok(reply) => {
+                self.history.push(assistant_message(&reply.text)?);
+                Ok(reply)
+            }
+            Err(e) => {
+                // the prompt was not answered, so it is not part of the history
+                self.history.pop();
+                Err(e)
+            }
+        }
+    }
+
+    async fn cancel(&mut self) -> Result<(), MorphaError> {
+        if self.pending {
+            self.history.pop();
+            self.pending = false;
+        }
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), MorphaError> {
+        // nothing is held remotely
+        Ok(())
+    }
+}
+
+/// Name of a finish reason as used by the OpenAI API
+pub fn finish_reason_name(reason: &FinishReason) -> &'static str {
+    match reason {
+        FinishReason::Stop => "stop",
+        FinishReason::Length => "length",
+        FinishReason::ToolCalls => "tool_calls",
+        FinishReason::ContentFilter => "content_filter",
+        FinishReason::FunctionCall => "function_call",
+    }
+}
+
+fn user_message(text: &str) -> Result<ChatCompletionRequestMessage, MorphaError> {
+    Ok(ChatCompletionRequestUserMessageArgs::default()
+        .content(text.to_string())
+        .build()?
+        .into())
+}
+
+fn assistant_message(text: &str) -> Result<ChatCompletionRequestMessage, MorphaError> {
+    Ok(ChatCompletionRequestAssistantMessageArgs::default()
+        .content(text.to_string())
+        .build()?
+        .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    /// The messages of the next request, as sent to the API
+    fn messages(chat: &Chat) -> Value {
+        serde_json::to_value(chat.request().unwrap()).unwrap()["messages"].clone()
+    }
+
+    fn message(prompt: &str, response: &str) -> Message {
+        Message {
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_load_history() {
+        let mut chat = Chat::new(Client::new(), "gpt-4o-mini", Policy::default());
+        let mut personality = Personality::new("Tutor", "Explain simply.");
+        personality.model = Some("gpt-4o".to_string());
+        let history = [message("Why?", "Because."), message("How?", "Like so.")];
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime
+            .block_on(chat.start(&personality, &history))
+            .unwrap();
+
+        // archived messages are replayed after the instructions as prompts and responses
+        assert_eq!(chat.model(), "gpt-4o");
+        assert_eq!(
+            messages(&chat),
+            json!([
+                {"role": "system", "content": "Explain simply."},
+                {"role": "user", "content": "Why?"},
+                {"role": "assistant", "content": "Because."},
+                {"role": "user", "content": "How?"},
+                {"role": "assistant", "content": "Like so."},
+            ])
+        );
+
+        // loading another history replaces it and keeps the instructions
+        runtime
+            .block_on(chat.load_history(&[message("Hi", "Hello.")]))
+            .unwrap();
+        assert_eq!(
+            messages(&chat),
+            json!([
+                {"role": "system", "content": "Explain simply."},
+                {"role": "user", "content": "Hi"},
+                {"role": "assistant", "content": "Hello."},
+            ])
+        );
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut chat = Chat::new(Client::new(), "gpt-4o-mini", Policy::default());
+        chat.history.push(user_message("Why?").unwrap());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // a prompt already answered stays in the history
+        runtime.block_on(chat.cancel()).unwrap();
+        assert_eq!(chat.history.len(), 1);
+        chat.pending = true;
+        runtime.block_on(chat.cancel()).unwrap();
+        assert!(chat.history.is_empty());
+        assert!(!chat.pending);
+    }
+}
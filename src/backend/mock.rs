@@ -0,0 +1,142 @@
+use crate::backend::{Backend, OnText, Reply};
+use crate::conversation::Message;
+use crate::error::MorphaError;
+use crate::personality::Personality;
+
+use async_openai::error::{ApiError, OpenAIError};
+use async_trait::async_trait;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// Number of characters passed to `on_text` at a time, imitating a stream
+const CHUNK_CHARS: usize = 4;
+
+/// What a mock backend was asked to do, for inspection once it is boxed
+#[derive(Debug, Default)]
+pub struct Log {
+    /// Instructions from the personality most recently adopted
+    pub instructions: Option<String>,
+    /// Names of the personalities switched to after the session started
+    pub personalities: Vec<String>,
+    /// Prompts of the history most recently loaded
+    pub history: Vec<String>,
+    /// Prompts sent, in order
+    pub prompts: Vec<String>,
+    /// Number of responses cancelled
+    pub cancelled: usize,
+    pub closed: bool,
+}
+
+/// How a mock backend answers a prompt
+enum Step {
+    Reply(String),
+    Fail(String),
+    Hang,
+}
+
+/// Backend answering prompts from a script instead of an API
+#[derive(Default)]
+pub struct Mock {
+    script: VecDeque<Step>,
+    /// Model of the personality adopted, reported in place of "mock"
+    model: Option<String>,
+    log: Rc<RefCell<Log>>,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer the next unanswered prompt with `text`
+    pub fn reply(mut self, text: &str) -> Self {
+        self.script.push_back(Step::Reply(text.to_string()));
+        self
+    }
+
+    /// Fail the next unanswered prompt with `message`
+    pub fn fail(mut self, message: &str) -> Self {
+        self.script.push_back(Step::Fail(message.to_string()));
+        self
+    }
+
+    /// Never answer the next unanswered prompt, as when a run is stuck
+    pub fn hang(mut self) -> Self {
+        self.script.push_back(Step::Hang);
+        self
+    }
+
+    /// Shared record of the calls made to the backend
+    pub fn log(&self) -> Rc<RefCell<Log>> {
+        Rc::clone(&self.log)
+    }
+}
+
+#[async_trait(?Send)]
+impl Backend for Mock {
+    async fn start(
+        &mut self,
+        personality: &Personality,
+        history: &[Message],
+    ) -> Result<(), MorphaError> {
+        self.log.borrow_mut().instructions = Some(personality.instructions.clone());
+        self.model = personality.model.clone();
+        self.load_history(history).await
+    }
+
+    async fn set_personality(&mut self, personality: &Personality) -> Result<(), MorphaError> {
+        let mut log = self.log.borrow_mut();
+        log.instructions = Some(personality.instructions.clone());
+        log.personalities.push(personality.name.clone());
+        self.model = personality.model.clone();
+        Ok(())
+    }
+
+    async fn load_history(&mut self, history: &[Message]) -> Result<(), MorphaError> {
+        self.log.borrow_mut().history = history.iter().map(|m| m.prompt.clone()).collect();
+        Ok(())
+    }
+
+    async fn send(&mut self, prompt: &str, on_text: OnText<'_>) -> Result<Reply, MorphaError> {
+        self.log.borrow_mut().prompts.push(prompt.to_string());
+        let text = match self.script.pop_front() {
+            Some(Step::Reply(text)) => text,
+            Some(Step::Fail(message)) => return Err(api_error(&message)),
+            Some(Step::Hang) => std::future::pending().await,
+            None => return Err(api_error("mock backend has no reply scripted")),
+        };
+        let chars: Vec<char> = text.chars().collect();
+        for chunk in chars.chunks(CHUNK_CHARS) {
+            on_text(&chunk.iter().collect::<String>())?;
+        }
+        Ok(Reply {
+            model: self.model.clone().unwrap_or_else(|| "mock".to_string()),
+            prompt_tokens: Some(prompt.split_whitespace().count() as i64),
+            completion_tokens: Some(text.split_whitespace().count() as i64),
+            run_id: Some(format!("mock-{}", self.log.borrow().prompts.len())),
+            status: "stop".to_string(),
+            text,
+        })
+    }
+
+    async fn cancel(&mut self) -> Result<(), MorphaError> {
+        self.log.borrow_mut().cancelled += 1;
+        Ok(())
+    }
+
+    async fn close(&mut self) -> Result<(), MorphaError> {
+        self.log.borrow_mut().closed = true;
+        Ok(())
+    }
+}
+
+/// An error as the API would return it
+fn api_error(message: &str) -> MorphaError {
+    MorphaError::Api(OpenAIError::ApiError(ApiError {
+        message: message.to_string(),
+        r#type: None,
+        param: None,
+        code: None,
+    }))
+}
@@ -0,0 +1,207 @@
+use crate::attachment::{self, Attachment};
+use crate::conversation::Message;
+use crate::error::MorphaError;
+use crate::export;
+use crate::session::Session;
+
+use serde_json::{json, Value};
+use std::io::{sink, Write};
+use std::path::PathBuf;
+
+/// One prompt of a batch script with the options for its turn
+#[derive(Debug, Default, PartialEq)]
+pub struct Turn {
+    /// Label copied to the result of the turn
+    pub id: Option<String>,
+    pub prompt: String,
+    /// Personality to switch to before the prompt, which answers the turns that follow too
+    pub persona: Option<String>,
+    /// Text files attached to the prompt
+    pub files: Vec<PathBuf>,
+}
+
+/// Turns of a batch script, either a prompt on each line or a JSON object on each line
+///
+/// Blank lines and lines starting with `#` are skipped. The script is read as JSON Lines if its
+/// first turn starts with `{`.
+pub fn parse(text: &str) -> Result<Vec<Turn>, String> {
+    let lines = text
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+    let jsonl = lines
+        .clone()
+        .next()
+        .is_some_and(|(_, l)| l.starts_with('{'));
+    lines
+        .map(|(number, line)| match jsonl {
+            true => turn(line).map_err(|e| format!("line {}: {}", number, e)),
+            false => Ok(Turn {
+                prompt: line.to_string(),
+                ..Default::default()
+            }),
+        })
+        .collect()
+}
+
+/// A turn of a JSON Lines script, as {"id": "boil", "prompt": "Why?", "persona": "tutor"}
+fn turn(line: &str) -> Result<Turn, String> {
+    let value: Value = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    let object = value.as_object().ok_or("expected a JSON object")?;
+    let mut turn = Turn::default();
+    for (key, value) in object {
+        let string = || {
+            value
+                .as_str()
+                .map(str::to_string)
+                .ok_or_else(|| format!("{} must be a string", key))
+        };
+        match key.as_str() {
+            "id" => {
+                turn.id = Some(match value {
+                    Value::Number(n) => n.to_string(),
+                    _ => string()?,
+                })
+            }
+            "prompt" => turn.prompt = string()?,
+            "persona" => turn.persona = Some(string()?),
+            "files" => {
+                let files = value.as_array().ok_or("files must be an array")?;
+                for file in files {
+                    let file = file.as_str().ok_or("files must be strings")?;
+                    turn.files.push(PathBuf::from(file));
+                }
+            }
+            _ => return Err(format!("unknown option: {}", key)),
+        }
+    }
+    if turn.prompt.trim().is_empty() {
+        return Err("missing prompt".to_string());
+    }
+    Ok(turn)
+}
+
+/// Counts of the turns of a batch run
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    pub turns: usize,
+    pub failed: usize,
+}
+
+/// Send the prompt of each turn in order in the conversation of the session, writing the
+/// responses to `out`, or a JSON object with the message or error of each turn if `json` is set
+///
+/// A turn that fails is reported and the turns after it still run, unless the interrupt
+/// cancelled it.
+pub async fn run(
+    session: &mut Session,
+    turns: &[Turn],
+    out: &mut dyn Write,
+    json: bool,
+) -> Result<Summary, MorphaError> {
+    let mut summary = Summary::default();
+    for (i, turn) in turns.iter().enumerate() {
+        summary.turns += 1;
+        if !json {
+            for line in turn.prompt.lines() {
+                writeln!(out, "> {}", line)?;
+            }
+            writeln!(out)?;
+        }
+        let result = match json {
+            true => exchange(session, turn, &mut sink()).await,
+            false => exchange(session, turn, &mut *out).await,
+        };
+        let mut value = match result {
+            Ok(message) => {
+                let mut value = export::message_json(&message);
+                value["error"] = Value::Null;
+                value
+            }
+            Err(e @ MorphaError::Cancelled) => return Err(e),
+            Err(e) => {
+                summary.failed += 1;
+                session.status.error(&format!("turn {}: {}", i + 1, e));
+                json!({"prompt": turn.prompt, "error": e.to_string()})
+            }
+        };
+        match json {
+            true => {
+                value["turn"] = json!(i + 1);
+                value["turn_id"] = json!(turn.id);
+                value["attachments"] = json!(turn.files);
+                writeln!(out, "{}", value)?;
+            }
+            false => writeln!(out)?,
+        }
+        out.flush()?;
+    }
+    Ok(summary)
+}
+
+/// Switch personality and attach files as the turn asks, then send its prompt
+async fn exchange(
+    session: &mut Session,
+    turn: &Turn,
+    out: &mut dyn Write,
+) -> Result<Message, MorphaError> {
+    if let Some(name) = &turn.persona {
+        session.persona(Some(name), &mut sink()).await?;
+    }
+    let attachments = turn
+        .files
+        .iter()
+        .map(|f| Attachment::read(f))
+        .collect::<Result<Vec<_>, _>>()?;
+    attachment::check_total(&attachments)?;
+    session.state.attachments = attachments;
+    session.exchange(turn.prompt.trim(), out).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lines() {
+        let turns = parse("# greeting\nHello\n\n  How are you?  \n").unwrap();
+        let prompts: Vec<&str> = turns.iter().map(|t| t.prompt.as_str()).collect();
+        assert_eq!(prompts, vec!["Hello", "How are you?"]);
+        assert!(turns
+            .iter()
+            .all(|t| t.persona.is_none() && t.files.is_empty()));
+        assert!(parse("\n# nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_parse_jsonl() {
+        let script = r#"
+            {"id": 1, "prompt": "Why does water boil?", "persona": "tutor"}
+            # attach the notes
+            {"id": "notes", "prompt": "Summarize.", "files": ["notes.md"]}
+        "#;
+        let turns = parse(script).unwrap();
+        assert_eq!(
+            turns[0],
+            Turn {
+                id: Some("1".to_string()),
+                prompt: "Why does water boil?".to_string(),
+                persona: Some("tutor".to_string()),
+                files: Vec::new(),
+            }
+        );
+        assert_eq!(turns[1].id.as_deref(), Some("notes"));
+        assert_eq!(turns[1].files, vec![PathBuf::from("notes.md")]);
+
+        assert_eq!(
+            parse("{\"prompt\": \"a\"}\n{\"prompt\": \"b\", \"colour\": 1}").unwrap_err(),
+            "line 2: unknown option: colour"
+        );
+        assert_eq!(parse("{\"id\": 1}").unwrap_err(), "line 1: missing prompt");
+        assert_eq!(
+            parse("{\"prompt\": \"a\"}\nplain").unwrap_err(),
+            "line 2: expected value at line 1 column 1"
+        );
+    }
+}
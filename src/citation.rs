@@ -0,0 +1,114 @@
+use crate::conversation::Message;
+use crate::database;
+use rusqlite::Connection;
+
+/// Introduction placed before cited messages so the assistant knows how to treat them
+const CITATION_PREAMBLE: &str = "The following messages are cited from our archived \
+conversations. Each appears between <cited_message> tags with the original prompt and \
+response. Use them as context for my request after the citations.";
+
+/// Format a message as a citation the assistant can reliably identify
+pub fn format_citation(message: &Message) -> String {
+    format!(
+        "<cited_message id=\"{}\" conversation=\"{}\" time=\"{}\">\n<prompt>\n{}\n</prompt>\n<response>\n{}\n</response>\n</cited_message>",
+        message.id.unwrap_or_default(),
+        message.conversation_id,
+        database::format_msec(message.msec),
+        message.prompt.trim(),
+        message.response.trim(),
+    )
+}
+
+/// Build the text sent to the assistant for `prompt` with cited messages attached
+pub fn cite(prompt: &str, citations: &[Message]) -> String {
+    if citations.is_empty() {
+        return prompt.to_string();
+    }
+    let mut text = format!("{}\n\n", CITATION_PREAMBLE);
+    for message in citations {
+        text.push_str(&format_citation(message));
+        text.push_str("\n\n");
+    }
+    text.push_str(prompt);
+    text
+}
+
+/// Record in the archive that `message_id` was sent with the cited messages
+pub fn write_to_database(
+    db: &Connection,
+    message_id: i64,
+    citations: &[Message],
+) -> rusqlite::Result<()> {
+    for cited in citations {
+        db.execute(
+            "INSERT INTO citations (message_id, cited_message_id) VALUES (?1, ?2)",
+            [Some(message_id), cited.id],
+        )?;
+    }
+    Ok(())
+}
+
+/// Ids of the messages cited when `message_id` was sent
+pub fn cited_by(db: &Connection, message_id: i64) -> rusqlite::Result<Vec<i64>> {
+    let mut stmt =
+        db.prepare("SELECT cited_message_id FROM citations WHERE message_id = ?1 ORDER BY rowid")?;
+    let rows = stmt.query_map([message_id], |row| row.get(0))?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: i64, prompt: &str) -> Message {
+        Message {
+            id: Some(id),
+            conversation_id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            msec: 0,
+            prompt: prompt.to_string(),
+            response: "It doesn't mean anything.".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_cite() {
+        assert_eq!(cite("plain prompt", &[]), "plain prompt");
+
+        let text = cite(
+            "Expand on this.",
+            &[
+                message(3, "What does Lorem Ipsum mean?"),
+                message(7, "Why?"),
+            ],
+        );
+        assert!(text.starts_with(CITATION_PREAMBLE));
+        assert!(text.ends_with("\n\nExpand on this."));
+        assert!(
+            text.contains("<cited_message id=\"3\" conversation=\"asst_7pF0CU0GNsBodf5XsVCcopFw\"")
+        );
+        assert!(text.contains("<prompt>\nWhat does Lorem Ipsum mean?\n</prompt>"));
+        assert!(text.contains("<cited_message id=\"7\""));
+    }
+
+    #[test]
+    fn test_write_to_database() {
+        let db = database::open_in_memory().unwrap();
+        crate::conversation::Conversation {
+            id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        let cited = message(1, "What does Lorem Ipsum mean?");
+        cited.write_to_database(&db).unwrap();
+        let id = message(2, "Expand on this.")
+            .write_to_database(&db)
+            .unwrap();
+
+        write_to_database(&db, id, &[cited]).unwrap();
+        assert_eq!(cited_by(&db, id).unwrap(), vec![1]);
+        assert!(cited_by(&db, 1).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1106 @@
+use crate::attachment::Attachment;
+use crate::conversation::{self, ConversationSummary, Message};
+use crate::database;
+use crate::explain::{Explanation, Source};
+use crate::markdown::CodeBlock;
+use rusqlite::Connection;
+use std::error::Error;
+use std::fmt;
+use std::io::Write;
+
+/// Prefix identifying a command in user input
+pub const PREFIX: char = '/';
+
+/// Number of entries shown per page of a listing
+pub const PAGE_SIZE: usize = 20;
+
+/// Maximum characters of a prompt or response shown in a listing
+const PREVIEW_CHARS: usize = 72;
+
+/// State carried from commands into the conversation
+#[derive(Default)]
+pub struct State {
+    /// Archived messages to attach to the next prompt
+    pub citations: Vec<Message>,
+    /// Archived material the next prompt asks to explain
+    pub explanation: Option<Explanation>,
+    /// Documents to attach to the next prompt
+    pub attachments: Vec<Attachment>,
+    /// Code blocks of the latest response
+    pub code: Vec<CodeBlock>,
+}
+
+/// State available to command handlers
+pub struct Context<'a> {
+    pub registry: &'a Registry,
+    pub db: &'a Connection,
+    pub out: &'a mut dyn Write,
+    pub state: &'a mut State,
+}
+
+/// What the main loop should do after a command has run
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Continue,
+    Quit,
+    /// Send a prompt to the assistant as if the user had entered it
+    Prompt(String),
+    /// Continue an archived conversation with its full history
+    Resume(String),
+    /// Switch to the named personality, or list those available if no name is given
+    Persona(Option<String>),
+}
+
+/// Function executing a command with its parsed arguments
+pub type Handler = fn(&mut Context, &[String]) -> Result<Action, Box<dyn Error>>;
+
+/// How many values an argument accepts
+#[derive(Debug, PartialEq)]
+pub enum ArgKind {
+    /// Exactly one value
+    Required,
+    /// Zero or one value
+    Optional,
+    /// One or more values, must be the last argument
+    Rest,
+}
+
+/// A named argument in a command specification
+pub struct Arg {
+    pub name: &'static str,
+    pub kind: ArgKind,
+}
+
+/// A command that can be entered at the prompt
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [Arg],
+    pub help: &'static str,
+    pub handler: Handler,
+}
+
+impl Command {
+    /// Usage line generated from the argument specification
+    pub fn usage(&self) -> String {
+        let mut usage = format!("{}{}", PREFIX, self.name);
+        for arg in self.args {
+            usage.push(' ');
+            usage.push_str(&match arg.kind {
+                ArgKind::Required => format!("<{}>", arg.name),
+                ArgKind::Optional => format!("[{}]", arg.name),
+                ArgKind::Rest => format!("<{}>...", arg.name),
+            });
+        }
+        usage
+    }
+
+    /// Verify the number of arguments satisfies the specification
+    fn check_args(&self, args: &[String]) -> Result<(), CommandError> {
+        let min = self
+            .args
+            .iter()
+            .filter(|a| a.kind != ArgKind::Optional)
+            .count();
+        let max = match self.args.last() {
+            Some(Arg {
+                kind: ArgKind::Rest,
+                ..
+            }) => usize::MAX,
+            _ => self.args.len(),
+        };
+        if args.len() < min || args.len() > max {
+            return Err(CommandError::Usage(self.usage()));
+        }
+        Ok(())
+    }
+}
+
+/// Errors encountered while interpreting a command line
+#[derive(Debug, PartialEq)]
+pub enum CommandError {
+    Empty,
+    UnterminatedQuote,
+    Unknown {
+        name: String,
+        suggestion: Option<String>,
+    },
+    Usage(String),
+    InvalidArgument(String),
+    /// An archived conversation or message, or a search result, does not exist
+    NotFound(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "no command given"),
+            CommandError::UnterminatedQuote => write!(f, "unterminated quote in command"),
+            CommandError::Unknown { name, suggestion } => {
+                write!(f, "unknown command: {}{}", PREFIX, name)?;
+                if let Some(s) = suggestion {
+                    write!(f, " (did you mean {}{}?)", PREFIX, s)?;
+                }
+                Ok(())
+            }
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::InvalidArgument(reason) => write!(f, "{}", reason),
+            CommandError::NotFound(what) => write!(f, "{}", what),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// A collection of commands available to the user
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Default for Registry {
+    /// A registry containing all built-in commands
+    fn default() -> Self {
+        let mut registry = Self::new();
+        registry.register(Command {
+            name: "help",
+            aliases: &["h", "?"],
+            args: &[Arg {
+                name: "command",
+                kind: ArgKind::Optional,
+            }],
+            help: "show available commands or help for a single command",
+            handler: help,
+        });
+        registry.register(Command {
+            name: "quit",
+            aliases: &["q", "exit"],
+            args: &[],
+            help: "leave the application",
+            handler: quit,
+        });
+        registry.register(Command {
+            name: "search",
+            aliases: &["s"],
+            args: &[Arg {
+                name: "terms",
+                kind: ArgKind::Rest,
+            }],
+            help: "search archived messages; all terms must match, quote a phrase to match it \
+                   exactly and end a term with * to match by prefix",
+            handler: search,
+        });
+        registry.register(Command {
+            name: "list",
+            aliases: &["ls"],
+            args: &[
+                Arg {
+                    name: "conversations|messages",
+                    kind: ArgKind::Required,
+                },
+                Arg {
+                    name: "conversation",
+                    kind: ArgKind::Optional,
+                },
+                Arg {
+                    name: "page",
+                    kind: ArgKind::Optional,
+                },
+            ],
+            help: "list archived conversations, or the messages of one conversation, \
+                   most recent conversations first",
+            handler: list,
+        });
+        registry.register(Command {
+            name: "cite",
+            aliases: &[],
+            args: &[Arg {
+                name: "message",
+                kind: ArgKind::Rest,
+            }],
+            help: "attach archived messages by id as context for your next prompt, \
+                   or `/cite clear` to remove them",
+            handler: cite,
+        });
+        registry.register(Command {
+            name: "explain",
+            aliases: &[],
+            args: &[
+                Arg {
+                    name: "conversation|message",
+                    kind: ArgKind::Required,
+                },
+                Arg {
+                    name: "focus",
+                    kind: ArgKind::Optional,
+                },
+            ],
+            help: "ask the assistant to explain an archived message or conversation in more \
+                   detail, optionally focusing on a quoted point",
+            handler: explain,
+        });
+        registry.register(Command {
+            name: "resume",
+            aliases: &[],
+            args: &[Arg {
+                name: "conversation",
+                kind: ArgKind::Required,
+            }],
+            help: "continue an archived conversation, sending its history to the assistant",
+            handler: resume,
+        });
+        registry.register(Command {
+            name: "persona",
+            aliases: &[],
+            args: &[Arg {
+                name: "name",
+                kind: ArgKind::Optional,
+            }],
+            help: "switch to a personality from the personalities directory for the prompts \
+                   that follow, or list the personalities available",
+            handler: persona,
+        });
+        registry.register(Command {
+            name: "code",
+            aliases: &[],
+            args: &[Arg {
+                name: "n",
+                kind: ArgKind::Optional,
+            }],
+            help: "print code block n of the latest response, or list its code blocks",
+            handler: code,
+        });
+        registry.register(Command {
+            name: "save",
+            aliases: &[],
+            args: &[
+                Arg {
+                    name: "n",
+                    kind: ArgKind::Required,
+                },
+                Arg {
+                    name: "path",
+                    kind: ArgKind::Required,
+                },
+                Arg {
+                    name: "--force",
+                    kind: ArgKind::Optional,
+                },
+            ],
+            help: "write code block n of the latest response to a new file, or replace it with --force",
+            handler: save,
+        });
+        registry
+    }
+}
+
+impl Registry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self {
+            commands: Vec::new(),
+        }
+    }
+
+    /// Add a command to the registry
+    pub fn register(&mut self, command: Command) {
+        self.commands.push(command);
+    }
+
+    /// Iterate over registered commands in registration order
+    pub fn commands(&self) -> impl Iterator<Item = &Command> {
+        self.commands.iter()
+    }
+
+    /// Find a command by name or alias, with or without the prefix
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        let name = name.strip_prefix(PREFIX).unwrap_or(name);
+        self.commands
+            .iter()
+            .find(|c| c.name == name || c.aliases.contains(&name))
+    }
+
+    /// Suggest the closest command name for a misspelled one
+    pub fn suggest(&self, name: &str) -> Option<&'static str> {
+        self.commands
+            .iter()
+            .flat_map(|c| {
+                std::iter::once(&c.name)
+                    .chain(c.aliases.iter())
+                    .map(move |n| (c, n))
+            })
+            .map(|(c, n)| (edit_distance(name, n), n.len(), c.name))
+            .filter(|(distance, len, _)| *distance <= 2 && distance < len)
+            .map(|(distance, _, name)| (distance, name))
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, name)| name)
+    }
+
+    /// Help text listing every command
+    pub fn help(&self) -> String {
+        let usages: Vec<(String, &Command)> =
+            self.commands.iter().map(|c| (c.usage(), c)).collect();
+        let width = usages.iter().map(|(u, _)| u.len()).max().unwrap_or(0);
+        let mut text = String::new();
+        for (usage, command) in usages {
+            text.push_str(&format!(
+                "{:width$}  {}\n",
+                usage,
+                command.help,
+                width = width
+            ));
+        }
+        text
+    }
+
+    /// Parse a command line and dispatch it to the matching handler
+    pub fn run(&self, ctx: &mut Context, line: &str) -> Result<Action, Box<dyn Error>> {
+        let mut args = parse_command(line)?;
+        if args.is_empty() {
+            return Err(CommandError::Empty.into());
+        }
+        let name = args.remove(0);
+        let name = name.strip_prefix(PREFIX).unwrap_or(&name).to_string();
+        let command = match self.find(&name) {
+            Some(c) => c,
+            None => {
+                let suggestion = self.suggest(&name).map(|s| s.to_string());
+                return Err(CommandError::Unknown { name, suggestion }.into());
+            }
+        };
+        command.check_args(&args)?;
+        (command.handler)(ctx, &args)
+    }
+}
+
+/// Split a command line into arguments.
+///
+/// Arguments are separated by whitespace. Single quotes at the start of an argument
+/// preserve text literally, double quotes allow `\"` and `\\` escapes, and a backslash
+/// outside of quotes escapes the following character. An apostrophe within a word, as
+/// in `don't`, is kept as it is.
+pub fn parse_command(line: &str) -> Result<Vec<String>, CommandError> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                    in_arg = false;
+                }
+            }
+            '\'' if !in_arg => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => arg.push(c),
+                        None => return Err(CommandError::UnterminatedQuote),
+                    }
+                }
+            }
+            '"' => {
+                in_arg = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => arg.push(c),
+                            Some(c) => {
+                                arg.push('\\');
+                                arg.push(c);
+                            }
+                            None => return Err(CommandError::UnterminatedQuote),
+                        },
+                        Some(c) => arg.push(c),
+                        None => return Err(CommandError::UnterminatedQuote),
+                    }
+                }
+            }
+            '\\' => {
+                in_arg = true;
+                if let Some(c) = chars.next() {
+                    arg.push(c);
+                }
+            }
+            c => {
+                in_arg = true;
+                arg.push(c);
+            }
+        }
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+/// Shorten text to a single line of at most `max_chars` characters
+pub fn preview(text: &str, max_chars: usize) -> String {
+    let line = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    if line.chars().count() <= max_chars {
+        return line;
+    }
+    let mut short: String = line.chars().take(max_chars.saturating_sub(3)).collect();
+    short.push_str("...");
+    short
+}
+
+/// Parse a 1-based page number into an offset for listing
+pub fn page_offset(page: Option<&String>) -> Result<usize, CommandError> {
+    let page = match page {
+        Some(p) => p
+            .parse::<usize>()
+            .ok()
+            .filter(|p| *p > 0)
+            .ok_or_else(|| CommandError::InvalidArgument(format!("invalid page: {}", p)))?,
+        None => 1,
+    };
+    Ok((page - 1) * PAGE_SIZE)
+}
+
+/// Resolve a full conversation id from an id or unambiguous prefix
+pub fn resolve_conversation_id(db: &Connection, id: &str) -> Result<String, Box<dyn Error>> {
+    let mut ids = conversation::find_conversation_ids(db, id)?;
+    if ids.contains(&id.to_string()) {
+        return Ok(id.to_string());
+    }
+    match ids.len() {
+        0 => Err(CommandError::NotFound(format!("no conversation found: {}", id)).into()),
+        1 => Ok(ids.remove(0)),
+        n => Err(CommandError::InvalidArgument(format!(
+            "{} conversations begin with {}, use a longer id",
+            n, id
+        ))
+        .into()),
+    }
+}
+
+/// Model, token usage, latency and status of a message, for those that are known
+pub fn message_details(message: &Message) -> String {
+    let mut details = Vec::new();
+    if let Some(assistant) = &message.assistant {
+        details.push(assistant.clone());
+    }
+    if let Some(model) = &message.model {
+        details.push(model.clone());
+    }
+    if let (Some(prompt), Some(completion)) = (message.prompt_tokens, message.completion_tokens) {
+        details.push(format!("{}+{} tokens", prompt, completion));
+    }
+    if let Some(latency) = message.latency_msec() {
+        details.push(format!("{:.1}s", latency as f64 / 1000.0));
+    }
+    if let Some(status) = &message.status {
+        details.push(status.clone());
+    }
+    match details.is_empty() {
+        true => String::new(),
+        false => format!("  ({})", details.join(", ")),
+    }
+}
+
+/// Write a listing of conversations
+pub fn write_conversations(
+    out: &mut dyn Write,
+    conversations: &[ConversationSummary],
+) -> std::io::Result<()> {
+    for c in conversations {
+        writeln!(
+            out,
+            "{}  {}  {:>3} messages  {}",
+            c.id,
+            database::format_msec(c.msec),
+            c.message_count,
+            preview(&c.first_prompt, PREVIEW_CHARS),
+        )?;
+    }
+    Ok(())
+}
+
+/// Write a listing of messages
+pub fn write_messages(out: &mut dyn Write, messages: &[Message]) -> std::io::Result<()> {
+    for m in messages {
+        writeln!(
+            out,
+            "[{}] {}{}",
+            m.id.unwrap_or_default(),
+            database::format_msec(m.msec),
+            message_details(m),
+        )?;
+        writeln!(out, "  > {}", preview(&m.prompt, PREVIEW_CHARS))?;
+        writeln!(out, "  {}\n", preview(&m.response, PREVIEW_CHARS))?;
+    }
+    Ok(())
+}
+
+/// Write the results of a search, best matches first
+pub fn write_search_results(
+    out: &mut dyn Write,
+    results: &[database::SearchResult],
+) -> std::io::Result<()> {
+    for result in results {
+        let m = &result.message;
+        writeln!(
+            out,
+            "[{}] {}  {}{}",
+            m.id.unwrap_or_default(),
+            m.conversation_id,
+            database::format_msec(m.msec),
+            message_details(m),
+        )?;
+        writeln!(out, "  > {}", result.prompt)?;
+        writeln!(out, "  {}\n", result.response.replace('\n', " "))?;
+    }
+    Ok(())
+}
+
+/// Number of single character edits required to turn `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Show help for all commands or a single command
+fn help(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    match args.first() {
+        Some(name) => match ctx.registry.find(name) {
+            Some(command) => {
+                writeln!(ctx.out, "{}\n    {}", command.usage(), command.help)?;
+                if !command.aliases.is_empty() {
+                    let aliases: Vec<String> = command
+                        .aliases
+                        .iter()
+                        .map(|a| format!("{}{}", PREFIX, a))
+                        .collect();
+                    writeln!(ctx.out, "    aliases: {}", aliases.join(", "))?;
+                }
+            }
+            None => {
+                let name = name.strip_prefix(PREFIX).unwrap_or(name).to_string();
+                let suggestion = ctx.registry.suggest(&name).map(|s| s.to_string());
+                return Err(CommandError::Unknown { name, suggestion }.into());
+            }
+        },
+        None => write!(ctx.out, "{}", ctx.registry.help())?,
+    }
+    Ok(Action::Continue)
+}
+
+/// Leave the application
+fn quit(_ctx: &mut Context, _args: &[String]) -> Result<Action, Box<dyn Error>> {
+    Ok(Action::Quit)
+}
+
+/// Search archived messages and print the best matches
+fn search(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    let results = database::search(ctx.db, &database::search_query(args))?;
+    if results.is_empty() {
+        writeln!(ctx.out, "no results for: {}", args.join(" "))?;
+    }
+    write_search_results(ctx.out, &results)?;
+    Ok(Action::Continue)
+}
+
+/// List archived conversations or the messages of a conversation
+fn list(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    let usage = || CommandError::Usage(ctx.registry.find("list").unwrap().usage());
+    match args[0].as_str() {
+        "conversations" | "c" => {
+            if args.len() > 2 {
+                return Err(usage().into());
+            }
+            let offset = page_offset(args.get(1))?;
+            let conversations = conversation::list_conversations(ctx.db, PAGE_SIZE, offset)?;
+            if conversations.is_empty() {
+                writeln!(ctx.out, "no conversations")?;
+            }
+            write_conversations(ctx.out, &conversations)?;
+        }
+        "messages" | "m" => {
+            let id = args.get(1).ok_or_else(usage)?;
+            let id = resolve_conversation_id(ctx.db, id)?;
+            let offset = page_offset(args.get(2))?;
+            let messages = conversation::list_messages(ctx.db, &id, PAGE_SIZE, offset)?;
+            if messages.is_empty() {
+                writeln!(ctx.out, "no messages")?;
+            }
+            write_messages(ctx.out, &messages)?;
+        }
+        _ => return Err(usage().into()),
+    }
+    Ok(Action::Continue)
+}
+
+/// Attach archived messages to the next prompt
+fn cite(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    if args == ["clear"] {
+        ctx.state.citations.clear();
+        writeln!(ctx.out, "citations cleared")?;
+        return Ok(Action::Continue);
+    }
+
+    // resolve every id before attaching any so a typo does not leave a partial citation
+    let mut messages = Vec::new();
+    for arg in args {
+        let id: i64 = arg
+            .trim_start_matches('#')
+            .parse()
+            .map_err(|_| CommandError::InvalidArgument(format!("invalid message id: {}", arg)))?;
+        match conversation::read_message(ctx.db, id)? {
+            Some(m) => messages.push(m),
+            None => return Err(CommandError::NotFound(format!("no message found: {}", id)).into()),
+        }
+    }
+    for message in messages {
+        if !ctx.state.citations.iter().any(|c| c.id == message.id) {
+            ctx.state.citations.push(message);
+        }
+    }
+    let ids: Vec<String> = ctx
+        .state
+        .citations
+        .iter()
+        .map(|m| m.id.unwrap_or_default().to_string())
+        .collect();
+    writeln!(ctx.out, "citing in your next prompt: {}", ids.join(", "))?;
+    Ok(Action::Continue)
+}
+
+/// Ask the assistant to expand upon an archived message or conversation
+fn explain(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    let source = match args[0].trim_start_matches('#').parse::<i64>() {
+        Ok(id) => Source::Message(id),
+        Err(_) => Source::Conversation(resolve_conversation_id(ctx.db, &args[0])?),
+    };
+    let explanation = Explanation {
+        source,
+        focus: args.get(1).cloned(),
+    };
+    let (messages, omitted) = explanation.messages(ctx.db)?;
+    if messages.is_empty() {
+        let reason = match &explanation.source {
+            Source::Message(id) => format!("no message found: {}", id),
+            Source::Conversation(id) => format!("no messages in conversation: {}", id),
+        };
+        return Err(CommandError::NotFound(reason).into());
+    }
+    if omitted > 0 {
+        writeln!(
+            ctx.out,
+            "citing the latest {} messages, leaving out {} earlier ones",
+            messages.len(),
+            omitted
+        )?;
+    }
+    for message in messages {
+        if !ctx.state.citations.iter().any(|c| c.id == message.id) {
+            ctx.state.citations.push(message);
+        }
+    }
+    let prompt = explanation.prompt(omitted);
+    ctx.state.explanation = Some(explanation);
+    Ok(Action::Prompt(prompt))
+}
+
+/// Continue an archived conversation
+fn resume(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    let id = resolve_conversation_id(ctx.db, &args[0])?;
+    Ok(Action::Resume(id))
+}
+
+/// Switch personality or list the personalities available
+fn persona(_ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    Ok(Action::Persona(args.first().cloned()))
+}
+
+/// Print a code block of the latest response, or list them all
+fn code(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    if let Some(n) = args.first() {
+        let block = code_block(ctx.state, n)?;
+        write!(ctx.out, "{}", block.code)?;
+        return Ok(Action::Continue);
+    }
+    if ctx.state.code.is_empty() {
+        writeln!(ctx.out, "no code blocks in the latest response")?;
+    }
+    for (i, block) in ctx.state.code.iter().enumerate() {
+        let lines = block.code.lines().count();
+        writeln!(
+            ctx.out,
+            "{}. {} ({} line{}) {}",
+            i + 1,
+            block.language.as_deref().unwrap_or("text"),
+            lines,
+            if lines == 1 { "" } else { "s" },
+            preview(&block.code, PREVIEW_CHARS)
+        )?;
+    }
+    Ok(Action::Continue)
+}
+
+/// Write a code block of the latest response to a file
+fn save(ctx: &mut Context, args: &[String]) -> Result<Action, Box<dyn Error>> {
+    let block = code_block(ctx.state, &args[0])?;
+    let path = &args[1];
+    let force = match args.get(2).map(String::as_str) {
+        None => false,
+        Some("--force") => true,
+        Some(option) => {
+            return Err(CommandError::InvalidArgument(format!("unknown option: {}", option)).into())
+        }
+    };
+    // a mistyped path must not destroy an existing file
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!force)
+        .open(path);
+    let mut file = match file {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+            let reason = format!("{} already exists, add --force to replace it", path);
+            return Err(CommandError::InvalidArgument(reason).into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    file.write_all(block.code.as_bytes())?;
+    writeln!(
+        ctx.out,
+        "saved code block {} to {} ({} bytes)",
+        args[0],
+        path,
+        block.code.len()
+    )?;
+    Ok(Action::Continue)
+}
+
+/// Code block `n` of the latest response, counting from 1
+fn code_block<'a>(state: &'a State, n: &str) -> Result<&'a CodeBlock, CommandError> {
+    let i: usize = n
+        .parse()
+        .map_err(|_| CommandError::InvalidArgument(format!("invalid code block: {}", n)))?;
+    match i.checked_sub(1).and_then(|i| state.code.get(i)) {
+        Some(block) => Ok(block),
+        None => Err(CommandError::InvalidArgument(format!(
+            "no code block {} in the latest response",
+            i
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // return an in-memory database connection with one archived message
+    fn setup() -> Connection {
+        let db = database::open_in_memory().unwrap();
+        conversation::Conversation {
+            id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        Message {
+            id: None,
+            conversation_id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            msec: 0,
+            prompt: "What does Lorem Ipsum mean?".to_string(),
+            response: "It doesn't mean anything.".to_string(),
+            ..Default::default()
+        }
+        .write_to_database(&db)
+        .unwrap();
+        db
+    }
+
+    // run a command line against an archive, returning the action and output
+    fn run_with(
+        db: &Connection,
+        state: &mut State,
+        line: &str,
+    ) -> (Result<Action, Box<dyn Error>>, String) {
+        let registry = Registry::default();
+        let mut out: Vec<u8> = Vec::new();
+        let mut ctx = Context {
+            registry: &registry,
+            db,
+            out: &mut out,
+            state,
+        };
+        let result = registry.run(&mut ctx, line);
+        (result, String::from_utf8(out).unwrap())
+    }
+
+    // run a command line against a fresh archive and state
+    fn run(line: &str) -> (Result<Action, Box<dyn Error>>, String) {
+        run_with(&setup(), &mut State::default(), line)
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview("short\n  text", 72), "short text");
+        assert_eq!(preview("abcdefghij", 8), "abcde...");
+    }
+
+    #[test]
+    fn test_message_details() {
+        let mut message = Message::default();
+        assert_eq!(message_details(&message), "");
+        message.model = Some("gpt-4-turbo".to_string());
+        message.assistant = Some("Morpha".to_string());
+        message.prompt_tokens = Some(12);
+        message.completion_tokens = Some(345);
+        message.started_msec = Some(1000);
+        message.finished_msec = Some(3400);
+        message.status = Some("completed".to_string());
+        assert_eq!(
+            message_details(&message),
+            "  (Morpha, gpt-4-turbo, 12+345 tokens, 2.4s, completed)"
+        );
+    }
+
+    #[test]
+    fn test_page_offset() {
+        assert_eq!(page_offset(None).unwrap(), 0);
+        assert_eq!(page_offset(Some(&"3".to_string())).unwrap(), 2 * PAGE_SIZE);
+        assert!(page_offset(Some(&"0".to_string())).is_err());
+        assert!(page_offset(Some(&"x".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_run_list() {
+        let (result, out) = run("/list conversations");
+        assert_eq!(result.unwrap(), Action::Continue);
+        assert!(out.starts_with("asst_7pF0CU0GNsBodf5XsVCcopFw  "));
+        assert!(out.ends_with("  1 messages  What does Lorem Ipsum mean?\n"));
+        let (_, out) = run("/list conversations 2");
+        assert_eq!(out, "no conversations\n");
+        let (_, out) = run("/list messages asst_7p");
+        assert!(out.starts_with("[1] "));
+        let (result, _) = run("/list messages");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "usage: /list <conversations|messages> [conversation] [page]"
+        );
+        let (result, _) = run("/list messages asst_missing");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no conversation found: asst_missing"
+        );
+    }
+
+    #[test]
+    fn test_run_cite() {
+        let db = setup();
+        let mut state = State::default();
+        let (result, out) = run_with(&db, &mut state, "/cite 1 #1");
+        assert_eq!(result.unwrap(), Action::Continue);
+        assert_eq!(out, "citing in your next prompt: 1\n");
+        assert_eq!(state.citations.len(), 1);
+        assert_eq!(state.citations[0].prompt, "What does Lorem Ipsum mean?");
+
+        let (result, _) = run_with(&db, &mut state, "/cite 2");
+        assert_eq!(result.unwrap_err().to_string(), "no message found: 2");
+        let (result, _) = run_with(&db, &mut state, "/cite x");
+        assert_eq!(result.unwrap_err().to_string(), "invalid message id: x");
+        assert_eq!(state.citations.len(), 1);
+
+        run_with(&db, &mut state, "/cite clear").0.unwrap();
+        assert!(state.citations.is_empty());
+    }
+
+    #[test]
+    fn test_run_explain() {
+        let db = setup();
+        let mut state = State::default();
+        let (result, _) = run_with(&db, &mut state, r#"/explain 1 "the meaning""#);
+        assert_eq!(
+            result.unwrap(),
+            Action::Prompt(
+                "Please explain the topic of cited message 1 in more detail, \
+                 with particular respect to: the meaning"
+                    .to_string()
+            )
+        );
+        assert_eq!(state.citations.len(), 1);
+        assert_eq!(
+            state.explanation,
+            Some(Explanation {
+                source: Source::Message(1),
+                focus: Some("the meaning".to_string()),
+            })
+        );
+
+        let (result, _) = run_with(&db, &mut state, "/explain 9");
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "no message found: 9");
+        assert!(matches!(
+            error.downcast_ref(),
+            Some(CommandError::NotFound(_))
+        ));
+        let (result, _) = run_with(&db, &mut state, "/explain asst_missing");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no conversation found: asst_missing"
+        );
+    }
+
+    #[test]
+    fn test_run_resume() {
+        let db = setup();
+        let (result, _) = run_with(&db, &mut State::default(), "/resume asst_7p");
+        assert_eq!(
+            result.unwrap(),
+            Action::Resume("asst_7pF0CU0GNsBodf5XsVCcopFw".to_string())
+        );
+    }
+
+    #[test]
+    fn test_run_persona() {
+        let db = setup();
+        let (result, _) = run_with(&db, &mut State::default(), "/persona tutor");
+        assert_eq!(result.unwrap(), Action::Persona(Some("tutor".to_string())));
+        let (result, _) = run_with(&db, &mut State::default(), "/persona");
+        assert_eq!(result.unwrap(), Action::Persona(None));
+    }
+
+    #[test]
+    fn test_run_code() {
+        let mut state = State::default();
+        let (_, output) = run_with(&setup(), &mut state, "/code");
+        assert_eq!(output, "no code blocks in the latest response\n");
+
+        state.code = crate::markdown::code_blocks("```sh\necho hi\n```\n```\na\nb\n```");
+        let (_, output) = run_with(&setup(), &mut state, "/code");
+        assert_eq!(output, "1. sh (1 line) echo hi\n2. text (2 lines) a b\n");
+        let (_, output) = run_with(&setup(), &mut state, "/code 2");
+        assert_eq!(output, "a\nb\n");
+        let (result, _) = run_with(&setup(), &mut state, "/code 3");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no code block 3 in the latest response"
+        );
+
+        let path = std::env::temp_dir().join(format!("morpha-save-{}.sh", std::process::id()));
+        let line = format!("/save 1 \"{}\"", path.display());
+        let (result, output) = run_with(&setup(), &mut state, &line);
+        assert_eq!(result.unwrap(), Action::Continue);
+        assert!(output.ends_with(" (8 bytes)\n"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo hi\n");
+
+        // an existing file is only replaced when forced
+        let line = format!("/save 2 \"{}\"", path.display());
+        let (result, _) = run_with(&setup(), &mut state, &line);
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .ends_with("already exists, add --force to replace it"));
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "echo hi\n");
+        let (result, _) = run_with(&setup(), &mut state, &format!("{} --force", line));
+        assert_eq!(result.unwrap(), Action::Continue);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "a\nb\n");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            parse_command(r#"/explain 42 "the pressure part""#).unwrap(),
+            vec!["/explain", "42", "the pressure part"]
+        );
+        assert_eq!(
+            parse_command(r#"/save 1 'my file.rs'  ~/a\ b "say \"hi\"""#).unwrap(),
+            vec!["/save", "1", "my file.rs", "~/a b", r#"say "hi""#]
+        );
+        assert_eq!(
+            parse_command(r#"a"b" 'c' """#).unwrap(),
+            vec!["ab", "c", ""]
+        );
+        assert_eq!(
+            parse_command("/explain 12 the user's point").unwrap(),
+            vec!["/explain", "12", "the", "user's", "point"]
+        );
+        assert_eq!(
+            parse_command("/search don't 'it''s'").unwrap(),
+            vec!["/search", "don't", "it's'"]
+        );
+        assert_eq!(parse_command("   ").unwrap(), Vec::<String>::new());
+        assert_eq!(
+            parse_command(r#"/search "unfinished"#),
+            Err(CommandError::UnterminatedQuote)
+        );
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("search", "search"), 0);
+        assert_eq!(edit_distance("serch", "search"), 1);
+        assert_eq!(edit_distance("qiut", "quit"), 2);
+        assert_eq!(edit_distance("", "help"), 4);
+    }
+
+    #[test]
+    fn test_command_usage() {
+        let registry = Registry::default();
+        assert_eq!(
+            registry.find("/search").unwrap().usage(),
+            "/search <terms>..."
+        );
+        assert_eq!(registry.find("h").unwrap().usage(), "/help [command]");
+    }
+
+    #[test]
+    fn test_run_quit_aliases() {
+        for line in ["/q", "/quit", "/exit"] {
+            assert_eq!(run(line).0.unwrap(), Action::Quit);
+        }
+    }
+
+    #[test]
+    fn test_run_unknown_command() {
+        let (result, _) = run("/serch ohaguro");
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "unknown command: /serch (did you mean /search?)"
+        );
+        let (result, _) = run("/zzzzzz");
+        assert_eq!(result.unwrap_err().to_string(), "unknown command: /zzzzzz");
+    }
+
+    #[test]
+    fn test_run_usage() {
+        let (result, _) = run("/search");
+        assert_eq!(result.unwrap_err().to_string(), "usage: /search <terms>...");
+        let (result, _) = run("/quit now");
+        assert_eq!(result.unwrap_err().to_string(), "usage: /quit");
+    }
+
+    #[test]
+    fn test_run_help() {
+        let (result, out) = run("/help");
+        assert_eq!(result.unwrap(), Action::Continue);
+        for command in Registry::default().commands() {
+            assert!(out.contains(&command.usage()));
+        }
+        let (_, out) = run("/help exit");
+        assert!(out.starts_with("/quit\n"));
+        assert!(out.contains("aliases: /q, /exit"));
+    }
+}
@@ -0,0 +1,408 @@
+use crate::backend::{Kind, Policy};
+use crate::error::MorphaError;
+use crate::highlight::Theme;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Model used when none is configured
+pub const MODEL_DEFAULT: &str = "gpt-4-turbo";
+
+/// Table holding the named profiles, as in `[profiles.work]`
+const PROFILES_TABLE: &str = "profiles";
+
+/// Settings that may be given in the configuration file, a profile or on the command line
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Options {
+    pub model: Option<String>,
+    pub backend: Option<Kind>,
+    /// SQLite database path
+    pub database: Option<String>,
+    /// File containing the assistant's instructions
+    pub personality: Option<String>,
+    /// Directory of personality files to choose from
+    pub personalities: Option<String>,
+    /// Name of the personality from the personalities directory to begin with
+    pub persona: Option<String>,
+    /// Characters per line of wrapped prose, with 0 disabling wrapping
+    pub wrap: Option<usize>,
+    /// Colors highlighting code blocks
+    pub theme: Option<Theme>,
+    pub api_base: Option<String>,
+    pub timeout: Option<u64>,
+    pub retries: Option<u32>,
+}
+
+impl Options {
+    /// Options with any set in `other` taking precedence over these
+    pub fn merge(self, other: Options) -> Options {
+        Options {
+            model: other.model.or(self.model),
+            backend: other.backend.or(self.backend),
+            database: other.database.or(self.database),
+            personality: other.personality.or(self.personality),
+            personalities: other.personalities.or(self.personalities),
+            persona: other.persona.or(self.persona),
+            wrap: other.wrap.or(self.wrap),
+            theme: other.theme.or(self.theme),
+            api_base: other.api_base.or(self.api_base),
+            timeout: other.timeout.or(self.timeout),
+            retries: other.retries.or(self.retries),
+        }
+    }
+}
+
+/// Contents of a configuration file: defaults for every session, and named profiles
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(try_from = "toml::Table")]
+pub struct ConfigFile {
+    pub defaults: Options,
+    pub profiles: BTreeMap<String, Options>,
+}
+
+impl ConfigFile {
+    /// Read a configuration file, which is empty if `path` does not exist
+    pub fn read(path: &Path) -> Result<Self, MorphaError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)
+                .map_err(|e| MorphaError::Config(format!("{}: {}", path.display(), e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Parse a TOML configuration file: settings, and `[profiles.<name>]` tables
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| match e.span() {
+            Some(span) => {
+                let line = text[..span.start].matches('\n').count() + 1;
+                format!("line {}: {}", line, message(&e))
+            }
+            None => message(&e),
+        })
+    }
+}
+
+impl TryFrom<toml::Table> for ConfigFile {
+    type Error = String;
+
+    /// Settings at the top level are the defaults, and every other table is a profile
+    fn try_from(mut table: toml::Table) -> Result<Self, String> {
+        let profiles = match table.remove(PROFILES_TABLE) {
+            Some(toml::Value::Table(profiles)) => profiles,
+            Some(_) => return Err(format!("{} must be a table", PROFILES_TABLE)),
+            None => toml::Table::new(),
+        };
+        let mut file = ConfigFile {
+            defaults: table.try_into().map_err(|e| message(&e))?,
+            profiles: BTreeMap::new(),
+        };
+        for (name, options) in profiles {
+            let options = options
+                .try_into()
+                .map_err(|e| format!("profile {}: {}", name, message(&e)))?;
+            file.profiles.insert(name, options);
+        }
+        Ok(file)
+    }
+}
+
+/// The message of an error, without the excerpt of the file
+fn message(e: &toml::de::Error) -> String {
+    e.message().trim().to_string()
+}
+
+/// The effective configuration of a session after merging every source
+#[derive(Debug, PartialEq)]
+pub struct Settings {
+    /// Name of the profile selected, if any
+    pub profile: Option<String>,
+    pub model: String,
+    pub backend: Kind,
+    pub database: PathBuf,
+    pub personality: PathBuf,
+    pub personalities: PathBuf,
+    /// Personality to begin with in place of the personality file
+    pub persona: Option<String>,
+    /// Characters per line of wrapped prose, or `None` to print responses unwrapped
+    pub wrap: Option<usize>,
+    pub theme: Theme,
+    pub api_base: Option<String>,
+    pub policy: Policy,
+}
+
+impl Settings {
+    /// Merge built-in defaults, the file's defaults, the selected profile and command line options,
+    /// each taking precedence over the ones before
+    pub fn resolve(
+        file: &ConfigFile,
+        profile: Option<&str>,
+        command_line: Options,
+        home: &Path,
+    ) -> Result<Self, MorphaError> {
+        let mut options = file.defaults.clone();
+        if let Some(name) = profile {
+            let selected = file
+                .profiles
+                .get(name)
+                .ok_or_else(|| MorphaError::Config(format!("no profile named: {}", name)))?;
+            options = options.merge(selected.clone());
+        }
+        let options = options.merge(command_line);
+        if options.timeout == Some(0) {
+            // every request would time out at once
+            return Err(MorphaError::Config(
+                "timeout must be at least 1 second".to_string(),
+            ));
+        }
+        let policy = Policy::default();
+        Ok(Self {
+            profile: profile.map(String::from),
+            model: options.model.unwrap_or_else(|| MODEL_DEFAULT.to_string()),
+            // chat creates nothing remotely for a session and works with compatible servers
+            backend: options.backend.unwrap_or(Kind::Chat),
+            database: match options.database {
+                Some(path) => expand_home(&path, home),
+                None => home.join(".morpha.sqlite3"),
+            },
+            personality: match options.personality {
+                Some(path) => expand_home(&path, home),
+                None => home.join(".morpha_profile"),
+            },
+            personalities: match options.personalities {
+                Some(path) => expand_home(&path, home),
+                None => config_dir(home).join("personalities"),
+            },
+            persona: options.persona,
+            wrap: match options.wrap {
+                Some(0) => None,
+                Some(wrap) => Some(wrap),
+                None => Some(crate::personality::MAX_CHARS_DEFAULT),
+            },
+            theme: options.theme.unwrap_or(Theme::Dark),
+            api_base: options.api_base,
+            policy: Policy {
+                timeout: options.timeout.map_or(policy.timeout, Duration::from_secs),
+                retries: options.retries.unwrap_or(policy.retries),
+                ..policy
+            },
+        })
+    }
+
+    /// The settings as a configuration file would give them
+    pub fn to_toml(&self) -> String {
+        let mut text = String::new();
+        if let Some(profile) = &self.profile {
+            text.push_str(&format!("# profile: {}\n", profile));
+        }
+        let backend = self.backend.to_possible_value().unwrap();
+        text.push_str(&format!("model = {}\n", quote(&self.model)));
+        text.push_str(&format!("backend = {}\n", quote(backend.get_name())));
+        text.push_str(&format!(
+            "database = {}\n",
+            quote(&self.database.to_string_lossy())
+        ));
+        text.push_str(&format!(
+            "personality = {}\n",
+            quote(&self.personality.to_string_lossy())
+        ));
+        text.push_str(&format!(
+            "personalities = {}\n",
+            quote(&self.personalities.to_string_lossy())
+        ));
+        if let Some(persona) = &self.persona {
+            text.push_str(&format!("persona = {}\n", quote(persona)));
+        }
+        text.push_str(&format!("wrap = {}\n", self.wrap.unwrap_or(0)));
+        let theme = self.theme.to_possible_value().unwrap();
+        text.push_str(&format!("theme = {}\n", quote(theme.get_name())));
+        if let Some(api_base) = &self.api_base {
+            text.push_str(&format!("api_base = {}\n", quote(api_base)));
+        }
+        text.push_str(&format!("timeout = {}\n", self.policy.timeout.as_secs()));
+        text.push_str(&format!("retries = {}\n", self.policy.retries));
+        text
+    }
+}
+
+/// Directory of the configuration file, following the XDG base directory convention
+pub fn config_dir(home: &Path) -> PathBuf {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home.join(".config"),
+    };
+    config_home.join("morpha")
+}
+
+/// Path of the configuration file
+pub fn default_path(home: &Path) -> PathBuf {
+    config_dir(home).join("config.toml")
+}
+
+/// Replace a leading `~` with the home directory
+fn expand_home(path: &str, home: &Path) -> PathBuf {
+    match path.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None if path == "~" => home.to_path_buf(),
+        None => PathBuf::from(path),
+    }
+}
+
+/// A string as a TOML basic string
+fn quote(value: &str) -> String {
+    format!(
+        "\"{}\"",
+        value
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n")
+            .replace('\t', "\\t")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+# used by every session
+model = "gpt-4o"
+wrap = 100 # characters
+
+[profiles.local]
+model = "llama3"
+backend = "chat"
+api_base = "http://localhost:11434/v1"
+database = "~/local.sqlite3"
+
+[profiles."work"]
+backend = "assistants"
+personality = '/etc/morpha/work#1'
+wrap = 0
+theme = "light"
+"#;
+
+    #[test]
+    fn test_parse() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        assert_eq!(file.defaults.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(file.defaults.wrap, Some(100));
+        assert_eq!(
+            file.profiles.keys().collect::<Vec<_>>(),
+            vec!["local", "work"]
+        );
+        let work = &file.profiles["work"];
+        assert_eq!(work.backend, Some(Kind::Assistants));
+        assert_eq!(work.personality.as_deref(), Some("/etc/morpha/work#1"));
+        assert_eq!(work.model, None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |text| ConfigFile::parse(text).unwrap_err();
+        for (text, message) in [
+            ("colour = \"red\"", "unknown field `colour`"),
+            ("wrap = \"wide\"", "invalid type: string \"wide\""),
+            ("retries = -1", "invalid value: integer `-1`"),
+            ("backend = \"bard\"", "unknown variant `bard`"),
+            ("theme = \"neon\"", "unknown variant `neon`"),
+            ("model = \"gpt", "line 1: invalid basic string"),
+            ("[servers]", "unknown field `servers`"),
+            (
+                "model = \"a\"\nmodel = \"b\"",
+                "line 2: duplicate key `model`",
+            ),
+            ("[profiles.a]\n[profiles.a]", "line 2: invalid table header"),
+            ("profiles = 1", "profiles must be a table"),
+            (
+                "[profiles.work]\ncolour = 1",
+                "profile work: unknown field `colour`",
+            ),
+        ] {
+            assert!(
+                error(text).starts_with(message),
+                "{}: {}",
+                text,
+                error(text)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let home = Path::new("/home/user");
+
+        let settings = Settings::resolve(&file, None, Options::default(), home).unwrap();
+        assert_eq!(settings.model, "gpt-4o");
+        assert_eq!(settings.backend, Kind::Chat);
+        assert_eq!(settings.database, home.join(".morpha.sqlite3"));
+        assert_eq!(settings.wrap, Some(100));
+        assert_eq!(settings.policy, Policy::default());
+
+        // the profile overrides the file's defaults
+        let settings = Settings::resolve(&file, Some("local"), Options::default(), home).unwrap();
+        assert_eq!(settings.model, "llama3");
+        assert_eq!(settings.database, home.join("local.sqlite3"));
+        assert_eq!(settings.wrap, Some(100));
+        let settings = Settings::resolve(&file, Some("work"), Options::default(), home).unwrap();
+        assert_eq!(settings.backend, Kind::Assistants);
+        assert_eq!(settings.wrap, None);
+        assert_eq!(settings.theme, Theme::Light);
+
+        // the command line overrides the profile
+        let command_line = Options {
+            model: Some("mistral".to_string()),
+            timeout: Some(30),
+            ..Default::default()
+        };
+        let settings = Settings::resolve(&file, Some("local"), command_line, home).unwrap();
+        assert_eq!(settings.model, "mistral");
+        assert_eq!(settings.policy.timeout, Duration::from_secs(30));
+
+        let result = Settings::resolve(&file, Some("home"), Options::default(), home);
+        assert!(matches!(result, Err(MorphaError::Config(_))));
+    }
+
+    #[test]
+    fn test_resolve_timeout() {
+        let home = Path::new("/home/user");
+        let file = ConfigFile::parse("timeout = 0").unwrap();
+        let error = Settings::resolve(&file, None, Options::default(), home).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "configuration error: timeout must be at least 1 second"
+        );
+
+        let command_line = Options {
+            timeout: Some(0),
+            ..Default::default()
+        };
+        let result = Settings::resolve(&ConfigFile::default(), None, command_line, home);
+        assert!(matches!(result, Err(MorphaError::Config(_))));
+    }
+
+    #[test]
+    fn test_to_toml() {
+        let file = ConfigFile::parse(CONFIG).unwrap();
+        let home = Path::new("/home/user");
+        let settings = Settings::resolve(&file, Some("local"), Options::default(), home).unwrap();
+        let text = settings.to_toml();
+        assert!(text.starts_with("# profile: local\nmodel = \"llama3\"\n"));
+
+        // printed settings read back as the same settings
+        let reread = ConfigFile::parse(&text).unwrap();
+        let resolved = Settings::resolve(&reread, None, Options::default(), home).unwrap();
+        assert_eq!(
+            resolved,
+            Settings {
+                profile: None,
+                ..settings
+            }
+        );
+    }
+}
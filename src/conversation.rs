@@ -1,10 +1,11 @@
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 
 /// An OpenAI conversation
+#[derive(Debug)]
 pub struct Conversation {
     pub id: String,
     pub messages: Vec<Message>,
-    pub msec: f64,
+    pub msec: i64,
 }
 
 impl Conversation {
@@ -12,33 +13,198 @@ impl Conversation {
     pub fn write_to_database(&self, db: &Connection) -> rusqlite::Result<()> {
         db.execute(
             "INSERT INTO conversations (id, msec) VALUES (?1, ?2)",
-            [&self.id, &self.msec.to_string()],
+            rusqlite::params![self.id, self.msec],
         )?;
         Ok(())
     }
 }
 
+/// Read an archived conversation and all of its messages
+pub fn read_conversation(db: &Connection, id: &str) -> rusqlite::Result<Option<Conversation>> {
+    let msec: Option<i64> = db
+        .query_row(
+            "SELECT msec FROM conversations WHERE id = ?1",
+            [id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match msec {
+        Some(msec) => Ok(Some(Conversation {
+            id: id.to_string(),
+            messages: list_messages(db, id, i64::MAX as usize, 0)?,
+            msec,
+        })),
+        None => Ok(None),
+    }
+}
+
+/// An archived conversation with details for listing
+pub struct ConversationSummary {
+    pub id: String,
+    pub msec: i64,
+    pub message_count: i64,
+    pub first_prompt: String,
+}
+
+/// List archived conversations, most recent first
+pub fn list_conversations(
+    db: &Connection,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<ConversationSummary>> {
+    let mut stmt = db.prepare(
+        "SELECT c.id, c.msec, count(m.id),
+            (SELECT prompt FROM messages WHERE conversation_id = c.id ORDER BY msec, id LIMIT 1)
+        FROM conversations c
+        LEFT JOIN messages m ON m.conversation_id = c.id
+        GROUP BY c.id
+        ORDER BY c.msec DESC
+        LIMIT ?1 OFFSET ?2",
+    )?;
+    let rows = stmt.query_map([limit, offset], |row| {
+        Ok(ConversationSummary {
+            id: row.get(0)?,
+            msec: row.get(1)?,
+            message_count: row.get(2)?,
+            first_prompt: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+        })
+    })?;
+    rows.collect()
+}
+
+/// List the messages of a conversation in the order they were exchanged
+pub fn list_messages(
+    db: &Connection,
+    conversation_id: &str,
+    limit: usize,
+    offset: usize,
+) -> rusqlite::Result<Vec<Message>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT {} FROM messages
+        WHERE conversation_id = ?1
+        ORDER BY msec, id
+        LIMIT ?2 OFFSET ?3",
+        message_columns("messages")
+    ))?;
+    let rows = stmt.query_map(
+        rusqlite::params![conversation_id, limit, offset],
+        Message::from_row,
+    )?;
+    rows.collect()
+}
+
+/// Read an archived message by id
+pub fn read_message(db: &Connection, id: i64) -> rusqlite::Result<Option<Message>> {
+    db.query_row(
+        &format!(
+            "SELECT {} FROM messages WHERE id = ?1",
+            message_columns("messages")
+        ),
+        [id],
+        Message::from_row,
+    )
+    .optional()
+}
+
+/// Find the ids of archived conversations beginning with `prefix`
+pub fn find_conversation_ids(db: &Connection, prefix: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = db
+        .prepare("SELECT id FROM conversations WHERE substr(id, 1, length(?1)) = ?1 ORDER BY id")?;
+    let rows = stmt.query_map([prefix], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Columns of the messages table read by `Message::from_row`, qualified by `table`
+pub fn message_columns(table: &str) -> String {
+    [
+        "id",
+        "conversation_id",
+        "msec",
+        "prompt",
+        "response",
+        "model",
+        "assistant",
+        "prompt_tokens",
+        "completion_tokens",
+        "started_msec",
+        "finished_msec",
+        "run_id",
+        "status",
+    ]
+    .map(|column| format!("{}.{}", table, column))
+    .join(", ")
+}
+
 /// A message exchange in the OpenAI conversation
+#[derive(Clone, Debug, Default)]
 pub struct Message {
+    pub id: Option<i64>,
     pub conversation_id: String,
-    pub msec: f64,
+    pub msec: i64,
     pub prompt: String,
     pub response: String,
+    /// Model that generated the response
+    pub model: Option<String>,
+    /// Name of the personality that responded
+    pub assistant: Option<String>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    /// Time the request was sent
+    pub started_msec: Option<i64>,
+    /// Time the response was received
+    pub finished_msec: Option<i64>,
+    pub run_id: Option<String>,
+    /// Final status of the run producing the response
+    pub status: Option<String>,
 }
 
 impl Message {
-    /// Write the message to the database
-    pub fn write_to_database(&self, db: &Connection) -> rusqlite::Result<()> {
+    /// Read a message from a row selected with `message_columns`
+    pub fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            conversation_id: row.get(1)?,
+            msec: row.get(2)?,
+            prompt: row.get(3)?,
+            response: row.get(4)?,
+            model: row.get(5)?,
+            assistant: row.get(6)?,
+            prompt_tokens: row.get(7)?,
+            completion_tokens: row.get(8)?,
+            started_msec: row.get(9)?,
+            finished_msec: row.get(10)?,
+            run_id: row.get(11)?,
+            status: row.get(12)?,
+        })
+    }
+
+    /// Milliseconds between sending the request and receiving the response
+    pub fn latency_msec(&self) -> Option<i64> {
+        Some(self.finished_msec? - self.started_msec?)
+    }
+
+    /// Write the message to the database, returning the id of the new row
+    pub fn write_to_database(&self, db: &Connection) -> rusqlite::Result<i64> {
         db.execute(
-            "INSERT INTO messages (conversation_id, msec, prompt, response) VALUES (?1, ?2, ?3, ?4)",
-            [
-                &self.conversation_id,
-                &self.msec.to_string(),
-                &self.prompt,
-                &self.response,
+            "INSERT INTO messages (conversation_id, msec, prompt, response, model, assistant,
+                prompt_tokens, completion_tokens, started_msec, finished_msec, run_id, status)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            rusqlite::params![
+                self.conversation_id,
+                self.msec,
+                self.prompt,
+                self.response,
+                self.model,
+                self.assistant,
+                self.prompt_tokens,
+                self.completion_tokens,
+                self.started_msec,
+                self.finished_msec,
+                self.run_id,
+                self.status,
             ],
         )?;
-        Ok(())
+        Ok(db.last_insert_rowid())
     }
 }
 
@@ -49,9 +215,7 @@ mod tests {
 
     // return an in-memory database connection
     fn setup() -> Result<Connection, rusqlite::Error> {
-        let db = Connection::open_in_memory()?;
-        database::write_schema(&db, include_str!("schema.sql"))?;
-        Ok(db)
+        database::open_in_memory()
     }
 
     #[test]
@@ -60,13 +224,15 @@ mod tests {
         let conversation = Conversation {
             id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
             messages: Vec::new(),
-            msec: 0.0,
+            msec: 0,
         };
         let message = Message {
+            id: None,
             conversation_id: conversation.id.clone(),
-            msec: 0.0,
+            msec: 0,
             prompt: "What does Lorem Ipsum mean?".to_string(),
             response: "It doesn't mean anything, you idiot!".to_string(),
+            ..Default::default()
         };
         conversation.write_to_database(&db).unwrap();
         message.write_to_database(&db).unwrap();
@@ -95,10 +261,12 @@ mod tests {
         let rows = stmt
             .query_map([], |row| {
                 Ok(Message {
+                    id: None,
                     conversation_id: row.get(0)?,
                     msec: row.get(1)?,
                     prompt: row.get(2)?,
                     response: row.get(3)?,
+                    ..Default::default()
                 })
             })
             .unwrap();
@@ -109,4 +277,154 @@ mod tests {
             assert_eq!(row.response, message.response);
         }
     }
+
+    #[test]
+    fn test_message_details_write_to_database() {
+        let db = setup().unwrap();
+        Conversation {
+            id: "asst_1".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        let id = Message {
+            conversation_id: "asst_1".to_string(),
+            prompt: "What does Lorem Ipsum mean?".to_string(),
+            response: "Nothing at all.".to_string(),
+            model: Some("gpt-4-turbo".to_string()),
+            assistant: Some("Morpha".to_string()),
+            prompt_tokens: Some(12),
+            completion_tokens: Some(4),
+            started_msec: Some(1000),
+            finished_msec: Some(2500),
+            run_id: Some("run_abc".to_string()),
+            status: Some("completed".to_string()),
+            ..Default::default()
+        }
+        .write_to_database(&db)
+        .unwrap();
+
+        let message = read_message(&db, id).unwrap().unwrap();
+        assert_eq!(message.model.as_deref(), Some("gpt-4-turbo"));
+        assert_eq!(message.assistant.as_deref(), Some("Morpha"));
+        assert_eq!(
+            (message.prompt_tokens, message.completion_tokens),
+            (Some(12), Some(4))
+        );
+        assert_eq!(message.latency_msec(), Some(1500));
+        assert_eq!(message.run_id.as_deref(), Some("run_abc"));
+        assert_eq!(message.status.as_deref(), Some("completed"));
+    }
+
+    #[test]
+    fn test_list_conversations() {
+        let db = setup().unwrap();
+        for (id, msec) in [("asst_older", 1), ("asst_newer", 2), ("asst_empty", 0)] {
+            Conversation {
+                id: id.to_string(),
+                messages: Vec::new(),
+                msec,
+            }
+            .write_to_database(&db)
+            .unwrap();
+        }
+        for (msec, prompt) in [(4, "second prompt"), (3, "first prompt")] {
+            Message {
+                id: None,
+                conversation_id: "asst_newer".to_string(),
+                msec,
+                prompt: prompt.to_string(),
+                response: "response".to_string(),
+                ..Default::default()
+            }
+            .write_to_database(&db)
+            .unwrap();
+        }
+
+        let conversations = list_conversations(&db, 10, 0).unwrap();
+        let ids: Vec<&str> = conversations.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["asst_newer", "asst_older", "asst_empty"]);
+        assert_eq!(conversations[0].message_count, 2);
+        assert_eq!(conversations[0].first_prompt, "first prompt");
+        assert_eq!(conversations[1].message_count, 0);
+        assert_eq!(conversations[1].first_prompt, "");
+
+        // paging
+        let conversations = list_conversations(&db, 1, 1).unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations[0].id, "asst_older");
+
+        // prefix lookup
+        assert_eq!(
+            find_conversation_ids(&db, "asst_n").unwrap(),
+            vec!["asst_newer"]
+        );
+        assert_eq!(find_conversation_ids(&db, "asst_").unwrap().len(), 3);
+        assert!(find_conversation_ids(&db, "asst_%").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_read_conversation() {
+        let db = setup().unwrap();
+        assert!(read_conversation(&db, "asst_1").unwrap().is_none());
+        Conversation {
+            id: "asst_1".to_string(),
+            messages: Vec::new(),
+            msec: 5,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        for prompt in ["first", "second"] {
+            Message {
+                id: None,
+                conversation_id: "asst_1".to_string(),
+                msec: 6,
+                prompt: prompt.to_string(),
+                response: "response".to_string(),
+                ..Default::default()
+            }
+            .write_to_database(&db)
+            .unwrap();
+        }
+        let conversation = read_conversation(&db, "asst_1").unwrap().unwrap();
+        assert_eq!(conversation.msec, 5);
+        let prompts: Vec<&str> = conversation
+            .messages
+            .iter()
+            .map(|m| m.prompt.as_str())
+            .collect();
+        assert_eq!(prompts, vec!["first", "second"]);
+    }
+
+    #[test]
+    fn test_list_messages() {
+        let db = setup().unwrap();
+        Conversation {
+            id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        for i in 0..5 {
+            let id = Message {
+                id: None,
+                conversation_id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+                msec: i,
+                prompt: format!("prompt {}", i),
+                response: format!("response {}", i),
+                ..Default::default()
+            }
+            .write_to_database(&db)
+            .unwrap();
+            assert_eq!(id, i + 1);
+        }
+        let messages = list_messages(&db, "asst_7pF0CU0GNsBodf5XsVCcopFw", 2, 2).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].id, Some(3));
+        assert_eq!(messages[0].prompt, "prompt 2");
+        assert_eq!(messages[1].response, "response 3");
+        assert!(list_messages(&db, "asst_other", 10, 0).unwrap().is_empty());
+    }
 }
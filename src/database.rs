@@ -1,20 +1,466 @@
+use crate::conversation::{message_columns, Message};
 use rusqlite::Connection;
 
+/// Maximum number of results returned by `search`
+pub const SEARCH_LIMIT: usize = 20;
+
+/// Markers placed around matched terms in search results
+pub const HIGHLIGHT_OPEN: &str = "**";
+pub const HIGHLIGHT_CLOSE: &str = "**";
+
+/// A message matching a full text search
+pub struct SearchResult {
+    pub message: Message,
+    /// Prompt with matched terms highlighted
+    pub prompt: String,
+    /// Excerpt of the response around matched terms
+    pub response: String,
+    pub rank: f64,
+}
+
+/// A step upgrading the database schema to `version`
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub apply: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+/// Every schema migration in the order they are applied
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "relational schema with keys, indexes and integer times",
+        apply: relational_schema,
+    },
+    Migration {
+        version: 2,
+        description: "model, token usage and timing of each message",
+        apply: |db| write_schema(db, include_str!("migrations/0002_message_details.sql")),
+    },
+    Migration {
+        version: 3,
+        description: "attachments sent with each prompt",
+        apply: |db| write_schema(db, include_str!("migrations/0003_attachments.sql")),
+    },
+];
+
+/// Tables of archives created before schema versioning
+const LEGACY_TABLES: [&str; 4] = ["conversations", "messages", "citations", "explanations"];
+
 /// Get the current time in milliseconds
-pub fn current_msec() -> f64 {
+pub fn current_msec() -> i64 {
     let now = std::time::SystemTime::now();
     let since_the_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
-    since_the_epoch.as_millis() as f64
+    since_the_epoch.as_millis() as i64
+}
+
+/// Format a time in milliseconds as a local date and time
+pub fn format_msec(msec: i64) -> String {
+    match chrono::DateTime::from_timestamp_millis(msec) {
+        Some(t) => t
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%d %H:%M")
+            .to_string(),
+        None => msec.to_string(),
+    }
 }
 
-/// Open an SQLite database
+/// Open an SQLite database, upgrading its schema to the latest version
 pub fn open_database(path: &str) -> rusqlite::Result<Connection> {
     let db = Connection::open(path)?;
-    write_schema(&db, include_str!("schema.sql"))?;
+    prepare(&db)?;
+    Ok(db)
+}
+
+/// Open an in-memory SQLite database with the latest schema
+pub fn open_in_memory() -> rusqlite::Result<Connection> {
+    let db = Connection::open_in_memory()?;
+    prepare(&db)?;
     Ok(db)
 }
 
+/// Migrate the schema and enforce foreign keys for the connection
+fn prepare(db: &Connection) -> rusqlite::Result<()> {
+    // foreign keys are enabled after migrating, since legacy tables are rebuilt
+    migrate(db)?;
+    db.pragma_update(None, "foreign_keys", true)
+}
+
 /// Write the database schema
 pub fn write_schema(conn: &Connection, schema: &str) -> rusqlite::Result<()> {
     conn.execute_batch(schema)
 }
+
+/// Get the schema version recorded in the database
+pub fn schema_version(db: &Connection) -> rusqlite::Result<i64> {
+    db.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+/// Apply every migration newer than the recorded schema version.
+///
+/// Each migration runs in its own transaction together with the version update,
+/// so an interrupted upgrade leaves the database at the last completed version.
+pub fn migrate(db: &Connection) -> rusqlite::Result<()> {
+    let version = schema_version(db)?;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        let tx = db.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Determine if a table exists in the database
+pub fn table_exists(db: &Connection, name: &str) -> rusqlite::Result<bool> {
+    let count: i64 = db.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Determine if a table has a column
+pub fn column_exists(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let count: i64 = db.query_row(
+        "SELECT count(*) FROM pragma_table_info(?1) WHERE name = ?2",
+        [table, column],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Migration 1: primary keys, foreign keys, indexes and integer times.
+///
+/// Archives created before schema versioning are renamed aside, the new schema is
+/// created and their rows are copied across. Message ids are kept, using the
+/// implicit rowid for the oldest archives that had no `id` column, duplicate
+/// conversation rows are merged, and conversations referenced only by messages
+/// are recreated so every message keeps its conversation.
+fn relational_schema(db: &Connection) -> rusqlite::Result<()> {
+    db.execute_batch(
+        "DROP TRIGGER IF EXISTS messages_search_insert;
+        DROP TRIGGER IF EXISTS messages_search_delete;
+        DROP TRIGGER IF EXISTS messages_search_update;
+        DROP TABLE IF EXISTS messages_search;",
+    )?;
+    let mut legacy = Vec::new();
+    for table in LEGACY_TABLES {
+        if table_exists(db, table)? {
+            db.execute_batch(&format!("ALTER TABLE {0} RENAME TO legacy_{0}", table))?;
+            legacy.push(table);
+        }
+    }
+
+    write_schema(db, include_str!("migrations/0001_relational_schema.sql"))?;
+
+    if legacy.contains(&"conversations") {
+        db.execute_batch(
+            "INSERT INTO conversations (id, msec)
+                SELECT id, CAST(coalesce(min(msec), 0) AS INTEGER) FROM legacy_conversations
+                WHERE id IS NOT NULL GROUP BY id;",
+        )?;
+    }
+    if legacy.contains(&"messages") {
+        let id = match column_exists(db, "legacy_messages", "id")? {
+            true => "id",
+            false => "rowid",
+        };
+        db.execute_batch(&format!(
+            "INSERT OR IGNORE INTO conversations (id, msec)
+                SELECT conversation_id, CAST(coalesce(min(msec), 0) AS INTEGER) FROM legacy_messages
+                WHERE conversation_id IS NOT NULL GROUP BY conversation_id;
+            INSERT INTO messages (id, conversation_id, msec, prompt, response)
+                SELECT {}, conversation_id, CAST(coalesce(msec, 0) AS INTEGER),
+                    coalesce(prompt, ''), coalesce(response, '')
+                FROM legacy_messages WHERE conversation_id IS NOT NULL;",
+            id
+        ))?;
+    }
+    if legacy.contains(&"citations") {
+        db.execute_batch(
+            "INSERT OR IGNORE INTO citations (message_id, cited_message_id)
+                SELECT message_id, cited_message_id FROM legacy_citations
+                WHERE message_id IN (SELECT id FROM messages)
+                AND cited_message_id IN (SELECT id FROM messages);",
+        )?;
+    }
+    if legacy.contains(&"explanations") {
+        db.execute_batch(
+            "INSERT OR IGNORE INTO explanations
+                (message_id, source_message_id, source_conversation_id, focus)
+                SELECT message_id,
+                    (SELECT id FROM messages WHERE id = source_message_id),
+                    (SELECT id FROM conversations WHERE id = source_conversation_id),
+                    focus
+                FROM legacy_explanations
+                WHERE message_id IN (SELECT id FROM messages);",
+        )?;
+    }
+
+    // drop referencing tables first
+    for table in legacy.iter().rev() {
+        db.execute_batch(&format!("DROP TABLE legacy_{}", table))?;
+    }
+    rebuild_search_index(db)
+}
+
+/// Rebuild the full text search index from the messages table
+pub fn rebuild_search_index(db: &Connection) -> rusqlite::Result<()> {
+    db.execute(
+        "INSERT INTO messages_search(messages_search) VALUES ('rebuild')",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Convert user supplied terms into an FTS5 query matching all of them.
+///
+/// Each term is quoted as a phrase so punctuation is matched literally rather
+/// than parsed as query syntax. A trailing `*` is kept to allow prefix searches.
+pub fn search_query<S: AsRef<str>>(terms: &[S]) -> String {
+    terms
+        .iter()
+        .map(|term| {
+            let term = term.as_ref();
+            let (term, prefix) = match term.strip_suffix('*') {
+                Some(t) => (t, "*"),
+                None => (term, ""),
+            };
+            format!("\"{}\"{}", term.replace('"', "\"\""), prefix)
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Search archived messages, returning the best matches first
+pub fn search(db: &Connection, query: &str) -> rusqlite::Result<Vec<SearchResult>> {
+    let mut stmt = db.prepare(&format!(
+        "SELECT {},
+            highlight(messages_search, 0, ?2, ?3),
+            snippet(messages_search, 1, ?2, ?3, '...', 24),
+            messages_search.rank
+        FROM messages_search
+        JOIN messages m ON m.id = messages_search.rowid
+        WHERE messages_search MATCH ?1
+        ORDER BY messages_search.rank
+        LIMIT ?4",
+        message_columns("m")
+    ))?;
+    let rows = stmt.query_map(
+        rusqlite::params![query, HIGHLIGHT_OPEN, HIGHLIGHT_CLOSE, SEARCH_LIMIT],
+        |row| {
+            Ok(SearchResult {
+                message: Message::from_row(row)?,
+                prompt: row.get(13)?,
+                response: row.get(14)?,
+                rank: row.get(15)?,
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversation::Conversation;
+
+    // return an in-memory database connection with a few archived messages
+    fn setup() -> Result<Connection, rusqlite::Error> {
+        let db = open_in_memory()?;
+        Conversation {
+            id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)?;
+        let exchanges = [
+            (
+                "What is ohaguro?",
+                "The Japanese practice of dyeing teeth black, known as ohaguro.",
+            ),
+            (
+                "How does pressure affect boiling?",
+                "Lower atmospheric pressure lowers the boiling point of water.",
+            ),
+        ];
+        for (prompt, response) in exchanges {
+            Message {
+                id: None,
+                conversation_id: "asst_7pF0CU0GNsBodf5XsVCcopFw".to_string(),
+                msec: 0,
+                prompt: prompt.to_string(),
+                response: response.to_string(),
+                ..Default::default()
+            }
+            .write_to_database(&db)?;
+        }
+        Ok(db)
+    }
+
+    #[test]
+    fn test_search_query() {
+        assert_eq!(search_query(&["boiling", "point"]), r#""boiling" "point""#);
+        assert_eq!(search_query(&["boiling point"]), r#""boiling point""#);
+        assert_eq!(search_query(&["boil*"]), r#""boil"*"#);
+        assert_eq!(search_query(&["say", r#""hi""#]), r#""say" """hi""""#);
+    }
+
+    #[test]
+    fn test_search() {
+        let db = setup().unwrap();
+        let results = search(&db, &search_query(&["ohaguro"])).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id.unwrap(), 1);
+        assert_eq!(results[0].prompt, "What is **ohaguro**?");
+        assert!(results[0].response.contains("**ohaguro**"));
+
+        // punctuation is matched literally instead of failing as query syntax
+        let results = search(&db, &search_query(&["water's", "boil*"])).unwrap();
+        assert!(results.is_empty());
+        let results = search(&db, &search_query(&["boil*"])).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id.unwrap(), 2);
+    }
+
+    #[test]
+    fn test_search_index_follows_deletes() {
+        let db = setup().unwrap();
+        db.execute("DELETE FROM messages WHERE id = 1", []).unwrap();
+        let results = search(&db, &search_query(&["ohaguro"])).unwrap();
+        assert!(results.is_empty());
+    }
+
+    #[test]
+    fn test_migrate_new_database() {
+        let db = setup().unwrap();
+        assert_eq!(
+            schema_version(&db).unwrap(),
+            MIGRATIONS.last().unwrap().version
+        );
+
+        // migrating again is a no-op
+        migrate(&db).unwrap();
+        assert_eq!(search(&db, &search_query(&["ohaguro"])).unwrap().len(), 1);
+
+        // messages must belong to an archived conversation
+        let orphan = Message {
+            id: None,
+            conversation_id: "asst_missing".to_string(),
+            msec: 0,
+            prompt: "prompt".to_string(),
+            response: "response".to_string(),
+            ..Default::default()
+        };
+        assert!(orphan.write_to_database(&db).is_err());
+
+        // deleting a conversation removes its messages from the archive and index
+        db.execute("DELETE FROM conversations", []).unwrap();
+        let count: i64 = db
+            .query_row("SELECT count(*) FROM messages", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+        assert!(search(&db, &search_query(&["ohaguro"])).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_original_schema() {
+        // the schema of the first releases, with times written through to_string()
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE messages(conversation_id TEXT, msec REAL, prompt TEXT, response TEXT);
+            CREATE TABLE conversations(id TEXT, msec REAL);
+            INSERT INTO conversations VALUES ('asst_1', '1701645807094');
+            INSERT INTO conversations VALUES ('asst_1', '1701645807094');
+            INSERT INTO messages VALUES ('asst_1', '1701645807100', 'deleted', 'deleted');
+            INSERT INTO messages VALUES ('asst_1', '1701645807200', 'kept prompt', 'kept response');
+            INSERT INTO messages VALUES ('asst_2', '1701645807300', 'orphan prompt', 'orphan');
+            DELETE FROM messages WHERE rowid = 1;",
+        )
+        .unwrap();
+        migrate(&db).unwrap();
+        db.pragma_update(None, "foreign_keys", true).unwrap();
+
+        let conversations: Vec<(String, i64)> = db
+            .prepare("SELECT id, msec FROM conversations ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(
+            conversations,
+            vec![
+                ("asst_1".to_string(), 1701645807094),
+                ("asst_2".to_string(), 1701645807300)
+            ]
+        );
+
+        // message ids are kept from the implicit rowid
+        let results = search(&db, &search_query(&["kept"])).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.id.unwrap(), 2);
+        assert_eq!(results[0].message.msec, 1701645807200);
+        assert_eq!(search(&db, &search_query(&["orphan"])).unwrap().len(), 1);
+        let violations: i64 = db
+            .query_row("SELECT count(*) FROM pragma_foreign_key_check", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(violations, 0);
+    }
+
+    #[test]
+    fn test_migrate_unversioned_schema() {
+        // the schema with message ids, citations and the search index, before versioning
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "CREATE TABLE messages(
+                id INTEGER PRIMARY KEY, conversation_id TEXT, msec REAL, prompt TEXT, response TEXT
+            );
+            CREATE TABLE conversations(id TEXT, msec REAL);
+            CREATE TABLE citations(
+                message_id INTEGER REFERENCES messages(id),
+                cited_message_id INTEGER REFERENCES messages(id)
+            );
+            CREATE TABLE explanations(
+                message_id INTEGER REFERENCES messages(id),
+                source_message_id INTEGER REFERENCES messages(id),
+                source_conversation_id TEXT,
+                focus TEXT
+            );
+            CREATE VIRTUAL TABLE messages_search USING fts5(
+                prompt, response, conversation_id UNINDEXED, content='messages', content_rowid='id'
+            );
+            INSERT INTO conversations VALUES ('asst_1', 1.0);
+            INSERT INTO messages VALUES (4, 'asst_1', 2.0, 'cited prompt', 'cited response');
+            INSERT INTO messages VALUES (9, 'asst_1', 3.0, 'explain it', 'explained');
+            INSERT INTO citations VALUES (9, 4);
+            INSERT INTO citations VALUES (9, 5);
+            INSERT INTO explanations VALUES (9, 4, 'asst_1', 'the point');",
+        )
+        .unwrap();
+        migrate(&db).unwrap();
+
+        let citations: Vec<(i64, i64)> = db
+            .prepare("SELECT message_id, cited_message_id FROM citations")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(citations, vec![(9, 4)]);
+        let focus: String = db
+            .query_row(
+                "SELECT focus FROM explanations WHERE message_id = 9 AND source_message_id = 4",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(focus, "the point");
+        let results = search(&db, &search_query(&["explained"])).unwrap();
+        assert_eq!(results[0].message.id.unwrap(), 9);
+    }
+}
@@ -0,0 +1,201 @@
+use async_openai::error::OpenAIError;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+/// Prefix of a stream error reporting the HTTP status of a failed request
+const STREAM_STATUS_PREFIX: &str = "Invalid status code: ";
+
+/// Errors ending an exchange with the assistant, or the session itself
+#[derive(Debug)]
+pub enum MorphaError {
+    /// The API returned an error or could not be reached
+    Api(OpenAIError),
+    /// The archive could not be read or written
+    Database(rusqlite::Error),
+    /// Reading input or printing output failed
+    Io(std::io::Error),
+    /// The configuration file is invalid or names a profile that does not exist
+    Config(String),
+    /// A file to import is not in a format that can be read
+    Import(String),
+    /// A file or piped input cannot be attached to a prompt
+    Attachment(String),
+    /// A batch script is not in a format that can be read
+    Batch(String),
+    /// A command could not be carried out
+    Command(Box<dyn Error>),
+    /// The response contained content that cannot be shown in the terminal
+    Unsupported(&'static str),
+    /// The assistant refused to respond
+    Refusal(String),
+    /// A run ended without a response
+    Run {
+        status: &'static str,
+        reason: Option<String>,
+    },
+    /// The response stream ended before the response was complete
+    StreamEnded,
+    /// The backend was used before its session started
+    NotStarted,
+    /// Nothing was received from the API for longer than the timeout
+    Timeout(Duration),
+    /// The user cancelled the pending response with Ctrl-C
+    Cancelled,
+    /// The user interrupted the session with Ctrl-C
+    Interrupted,
+}
+
+impl MorphaError {
+    /// Whether the request may succeed if it is repeated
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MorphaError::Timeout(_) => true,
+            MorphaError::Api(OpenAIError::Reqwest(_)) => true,
+            MorphaError::Api(OpenAIError::ApiError(e)) => {
+                e.code.as_deref() == Some("rate_limit_exceeded")
+                    || e.r#type.as_deref() == Some("server_error")
+            }
+            MorphaError::Api(OpenAIError::StreamError(e)) => {
+                match e.strip_prefix(STREAM_STATUS_PREFIX) {
+                    Some(status) => status.starts_with("429") || status.starts_with('5'),
+                    // the connection failed rather than the request
+                    None => e.starts_with("error sending request"),
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+impl MorphaError {
+    /// Whether the API rejected the request itself, as a server does for fields it does not know
+    pub fn is_bad_request(&self) -> bool {
+        match self {
+            MorphaError::Api(OpenAIError::StreamError(e)) => e
+                .strip_prefix(STREAM_STATUS_PREFIX)
+                .is_some_and(|status| status.starts_with("400")),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for MorphaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MorphaError::Api(e) => write!(f, "api error: {}", e),
+            MorphaError::Database(e) => write!(f, "database error: {}", e),
+            MorphaError::Io(e) => write!(f, "i/o error: {}", e),
+            MorphaError::Config(e) => write!(f, "configuration error: {}", e),
+            MorphaError::Import(e) => write!(f, "import error: {}", e),
+            MorphaError::Attachment(e) => write!(f, "cannot attach {}", e),
+            MorphaError::Batch(e) => write!(f, "batch script error: {}", e),
+            MorphaError::Command(e) => write!(f, "{}", e),
+            MorphaError::Unsupported(what) => {
+                write!(f, "{} are not supported in the terminal", what)
+            }
+            MorphaError::Refusal(reason) => write!(f, "response generated a refusal: {}", reason),
+            MorphaError::Run { status, reason } => {
+                write!(f, "run ended with status {}", status)?;
+                if let Some(reason) = reason {
+                    write!(f, ": {}", reason)?;
+                }
+                Ok(())
+            }
+            MorphaError::StreamEnded => {
+                write!(f, "response stream ended before the response was complete")
+            }
+            MorphaError::NotStarted => write!(f, "assistant session has not started"),
+            MorphaError::Timeout(timeout) => {
+                write!(f, "no response within {} seconds", timeout.as_secs())
+            }
+            MorphaError::Cancelled => write!(f, "response cancelled"),
+            MorphaError::Interrupted => write!(f, "interrupted"),
+        }
+    }
+}
+
+impl Error for MorphaError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MorphaError::Api(e) => Some(e),
+            MorphaError::Database(e) => Some(e),
+            MorphaError::Io(e) => Some(e),
+            MorphaError::Command(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<OpenAIError> for MorphaError {
+    fn from(e: OpenAIError) -> Self {
+        MorphaError::Api(e)
+    }
+}
+
+impl From<rusqlite::Error> for MorphaError {
+    fn from(e: rusqlite::Error) -> Self {
+        MorphaError::Database(e)
+    }
+}
+
+impl From<std::io::Error> for MorphaError {
+    fn from(e: std::io::Error) -> Self {
+        MorphaError::Io(e)
+    }
+}
+
+impl From<Box<dyn Error>> for MorphaError {
+    fn from(e: Box<dyn Error>) -> Self {
+        MorphaError::Command(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let e = MorphaError::Run {
+            status: "failed",
+            reason: Some("rate limited".to_string()),
+        };
+        assert_eq!(e.to_string(), "run ended with status failed: rate limited");
+        let e = MorphaError::Run {
+            status: "expired",
+            reason: None,
+        };
+        assert_eq!(e.to_string(), "run ended with status expired");
+        assert_eq!(
+            MorphaError::Unsupported("images").to_string(),
+            "images are not supported in the terminal"
+        );
+    }
+
+    #[test]
+    fn test_is_transient() {
+        let api = |code: Option<&str>, r#type: Option<&str>| {
+            MorphaError::Api(OpenAIError::ApiError(async_openai::error::ApiError {
+                message: "".to_string(),
+                r#type: r#type.map(String::from),
+                param: None,
+                code: code.map(String::from),
+            }))
+        };
+        assert!(api(Some("rate_limit_exceeded"), Some("requests")).is_transient());
+        assert!(api(None, Some("server_error")).is_transient());
+        assert!(!api(Some("invalid_api_key"), Some("invalid_request_error")).is_transient());
+        assert!(!api(None, Some("insufficient_quota")).is_transient());
+
+        let stream = |e: &str| MorphaError::Api(OpenAIError::StreamError(e.to_string()));
+        assert!(stream("Invalid status code: 429 Too Many Requests").is_transient());
+        assert!(stream("Invalid status code: 503 Service Unavailable").is_transient());
+        assert!(!stream("Invalid status code: 401 Unauthorized").is_transient());
+        assert!(stream("error sending request for url (http://localhost/)").is_transient());
+        assert!(!stream("Invalid header value: \"text/html\"").is_transient());
+
+        assert!(MorphaError::Timeout(Duration::from_secs(1)).is_transient());
+        assert!(!MorphaError::Cancelled.is_transient());
+    }
+}
@@ -0,0 +1,183 @@
+use crate::conversation::{self, Message};
+use rusqlite::Connection;
+
+/// Maximum number of messages of a conversation cited for an explanation
+pub const CONVERSATION_MESSAGES_MAX: usize = 50;
+
+/// Archived material an explanation expands upon
+#[derive(Debug, PartialEq)]
+pub enum Source {
+    Message(i64),
+    Conversation(String),
+}
+
+/// A request for the assistant to expand on archived material
+#[derive(Debug, PartialEq)]
+pub struct Explanation {
+    pub source: Source,
+    pub focus: Option<String>,
+}
+
+impl Explanation {
+    /// Read the archived messages to cite for the explanation, the latest of a long conversation,
+    /// and the number of earlier messages left out
+    pub fn messages(&self, db: &Connection) -> rusqlite::Result<(Vec<Message>, usize)> {
+        match &self.source {
+            Source::Message(id) => {
+                let message = conversation::read_message(db, *id)?;
+                Ok((message.into_iter().collect(), 0))
+            }
+            Source::Conversation(id) => {
+                let count: usize = db.query_row(
+                    "SELECT COUNT(*) FROM messages WHERE conversation_id = ?1",
+                    [id],
+                    |row| row.get(0),
+                )?;
+                let omitted = count.saturating_sub(CONVERSATION_MESSAGES_MAX);
+                let messages =
+                    conversation::list_messages(db, id, CONVERSATION_MESSAGES_MAX, omitted)?;
+                Ok((messages, omitted))
+            }
+        }
+    }
+
+    /// The prompt asking the assistant to explain the cited material, of which `omitted` earlier
+    /// messages were left out
+    pub fn prompt(&self, omitted: usize) -> String {
+        let subject = match &self.source {
+            Source::Message(id) => format!("the topic of cited message {}", id),
+            Source::Conversation(_) => "the topic of the cited conversation".to_string(),
+        };
+        let mut prompt = match &self.focus {
+            Some(focus) => format!(
+                "Please explain {} in more detail, with particular respect to: {}",
+                subject, focus
+            ),
+            None => format!("Please explain {} in more detail.", subject),
+        };
+        if omitted > 0 {
+            prompt.push_str(&format!(
+                "\n\nOnly the latest messages of the conversation are cited; \
+                {} earlier messages are left out.",
+                omitted
+            ));
+        }
+        prompt
+    }
+
+    /// Record in the archive that `message_id` explained the source
+    pub fn write_to_database(&self, db: &Connection, message_id: i64) -> rusqlite::Result<()> {
+        let (source_message_id, source_conversation_id) = match &self.source {
+            Source::Message(id) => (Some(*id), None),
+            Source::Conversation(id) => (None, Some(id.as_str())),
+        };
+        db.execute(
+            "INSERT INTO explanations (message_id, source_message_id, source_conversation_id, focus)
+            VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![
+                message_id,
+                source_message_id,
+                source_conversation_id,
+                self.focus
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    #[test]
+    fn test_explanation_prompt() {
+        let explanation = Explanation {
+            source: Source::Message(42),
+            focus: Some("the pressure part".to_string()),
+        };
+        assert_eq!(
+            explanation.prompt(0),
+            "Please explain the topic of cited message 42 in more detail, \
+             with particular respect to: the pressure part"
+        );
+        let explanation = Explanation {
+            source: Source::Conversation("asst_1".to_string()),
+            focus: None,
+        };
+        assert_eq!(
+            explanation.prompt(0),
+            "Please explain the topic of the cited conversation in more detail."
+        );
+        assert!(explanation.prompt(12).ends_with(
+            "in more detail.\n\nOnly the latest messages of the conversation are cited; \
+             12 earlier messages are left out."
+        ));
+    }
+
+    #[test]
+    fn test_explanation_messages() {
+        let db = database::open_in_memory().unwrap();
+        conversation::Conversation {
+            id: "asst_1".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        for msec in 0..CONVERSATION_MESSAGES_MAX as i64 + 2 {
+            Message {
+                conversation_id: "asst_1".to_string(),
+                msec,
+                ..Default::default()
+            }
+            .write_to_database(&db)
+            .unwrap();
+        }
+        let explanation = Explanation {
+            source: Source::Conversation("asst_1".to_string()),
+            focus: None,
+        };
+        // a long conversation is cited from its latest messages
+        let (messages, omitted) = explanation.messages(&db).unwrap();
+        assert_eq!(messages.len(), CONVERSATION_MESSAGES_MAX);
+        assert_eq!(omitted, 2);
+        assert_eq!(messages[0].msec, 2);
+    }
+
+    #[test]
+    fn test_explanation_write_to_database() {
+        let db = database::open_in_memory().unwrap();
+        conversation::Conversation {
+            id: "asst_1".to_string(),
+            messages: Vec::new(),
+            msec: 0,
+        }
+        .write_to_database(&db)
+        .unwrap();
+        let id = Message {
+            id: None,
+            conversation_id: "asst_1".to_string(),
+            msec: 0,
+            prompt: "Please explain".to_string(),
+            response: "Certainly".to_string(),
+            ..Default::default()
+        }
+        .write_to_database(&db)
+        .unwrap();
+        let explanation = Explanation {
+            source: Source::Conversation("asst_1".to_string()),
+            focus: Some("boiling".to_string()),
+        };
+        explanation.write_to_database(&db, id).unwrap();
+        let row: (i64, Option<i64>, String, String) = db
+            .query_row(
+                "SELECT message_id, source_message_id, source_conversation_id, focus
+                FROM explanations",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(row, (id, None, "asst_1".to_string(), "boiling".to_string()));
+    }
+}
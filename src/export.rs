@@ -0,0 +1,262 @@
+use crate::conversation::{Conversation, Message};
+
+use serde_json::{json, Value};
+
+/// Delimiter around the response of a plain text export
+const TEXT_DELIMITER: &str = "----";
+
+/// Words of the first response naming a conversation
+const NAME_WORDS: usize = 6;
+
+/// File formats conversations are exported in
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// Markdown document with a section for each exchange
+    Markdown,
+    /// JSON object with metadata and an array of messages
+    Json,
+    /// JSON object for each message, one per line
+    Jsonl,
+    /// Standalone HTML page
+    Html,
+    /// Responses under a uuid, name and timestamp header
+    Text,
+}
+
+impl Format {
+    /// Extension of files exported in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Format::Markdown => "md",
+            Format::Json => "json",
+            Format::Jsonl => "jsonl",
+            Format::Html => "html",
+            Format::Text => "txt",
+        }
+    }
+}
+
+/// Name of a conversation taken from the first words of its first response
+pub fn name(conversation: &Conversation) -> String {
+    let Some(message) = conversation.messages.first() else {
+        return String::new();
+    };
+    message
+        .response
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .take(NAME_WORDS)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A conversation written out in `format`
+pub fn export(conversation: &Conversation, format: Format) -> String {
+    match format {
+        Format::Markdown => markdown(conversation),
+        Format::Json => {
+            let mut text = serde_json::to_string_pretty(&json(conversation)).unwrap();
+            text.push('\n');
+            text
+        }
+        Format::Jsonl => conversation
+            .messages
+            .iter()
+            .map(|m| format!("{}\n", message_json(m)))
+            .collect(),
+        Format::Html => html(conversation),
+        Format::Text => text(conversation),
+    }
+}
+
+/// Time in milliseconds as an RFC 3339 timestamp in local time, as 2023-12-03T15:23:27.094000-08:00
+pub fn timestamp(msec: i64) -> String {
+    match chrono::DateTime::from_timestamp_millis(msec) {
+        Some(t) => t
+            .with_timezone(&chrono::Local)
+            .format("%Y-%m-%dT%H:%M:%S%.6f%:z")
+            .to_string(),
+        None => msec.to_string(),
+    }
+}
+
+fn json(conversation: &Conversation) -> Value {
+    json!({
+        "id": conversation.id,
+        "name": name(conversation),
+        "timestamp": timestamp(conversation.msec),
+        "messages": conversation.messages.iter().map(message_json).collect::<Vec<_>>(),
+    })
+}
+
+/// A message with its metadata as a JSON object
+pub fn message_json(message: &Message) -> Value {
+    json!({
+        "id": message.id,
+        "conversation_id": message.conversation_id,
+        "timestamp": timestamp(message.msec),
+        "prompt": message.prompt,
+        "response": message.response,
+        "model": message.model,
+        "assistant": message.assistant,
+        "prompt_tokens": message.prompt_tokens,
+        "completion_tokens": message.completion_tokens,
+        "started": message.started_msec.map(timestamp),
+        "finished": message.finished_msec.map(timestamp),
+        "run_id": message.run_id,
+        "status": message.status,
+    })
+}
+
+/// Model, personality and time of a message, separated by commas
+fn details(message: &Message) -> String {
+    let mut details: Vec<String> = Vec::new();
+    details.extend(message.assistant.clone());
+    details.extend(message.model.clone());
+    details.push(timestamp(message.msec));
+    details.join(", ")
+}
+
+fn markdown(conversation: &Conversation) -> String {
+    let mut text = format!("# {}\n\n", name(conversation));
+    text.push_str(&format!("- id: {}\n", conversation.id));
+    text.push_str(&format!("- timestamp: {}\n", timestamp(conversation.msec)));
+    text.push_str(&format!("- messages: {}\n", conversation.messages.len()));
+    for message in &conversation.messages {
+        text.push_str(&format!("\n## Prompt\n\n{}\n", message.prompt.trim_end()));
+        text.push_str(&format!("\n## Response\n\n*{}*\n\n", details(message)));
+        text.push_str(&format!("{}\n", message.response.trim_end()));
+    }
+    text
+}
+
+fn html(conversation: &Conversation) -> String {
+    let name = escape(&name(conversation));
+    let mut text = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>pre {{ white-space: pre-wrap; }}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+        name, name
+    );
+    text.push_str(&format!(
+        "<p>{} &middot; {}</p>\n",
+        escape(&conversation.id),
+        timestamp(conversation.msec)
+    ));
+    for message in &conversation.messages {
+        text.push_str(&format!(
+            "<h2>Prompt</h2>\n<pre>{}</pre>\n",
+            escape(message.prompt.trim_end())
+        ));
+        text.push_str(&format!(
+            "<h2>Response</h2>\n<p><em>{}</em></p>\n<pre>{}</pre>\n",
+            escape(&details(message)),
+            escape(message.response.trim_end())
+        ));
+    }
+    text.push_str("</body>\n</html>\n");
+    text
+}
+
+/// The format of responses saved by hand in `design/*.txt`
+fn text(conversation: &Conversation) -> String {
+    let mut text = format!(
+        "uuid: {}\nname: {}\ntimestamp: {}\n{}\n",
+        conversation.id,
+        name(conversation),
+        timestamp(conversation.msec),
+        TEXT_DELIMITER
+    );
+    for message in &conversation.messages {
+        text.push_str(message.response.trim_end());
+        text.push_str(&format!("\n{}\n", TEXT_DELIMITER));
+    }
+    text
+}
+
+/// Text with the characters HTML reserves replaced by entities
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conversation() -> Conversation {
+        let message = |prompt: &str, response: &str| Message {
+            id: Some(1),
+            conversation_id: "8dd3163f".to_string(),
+            msec: 1701645807094,
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+            model: Some("gpt-4-turbo".to_string()),
+            assistant: Some("Morpha".to_string()),
+            ..Default::default()
+        };
+        Conversation {
+            id: "8dd3163f".to_string(),
+            messages: vec![
+                message(
+                    "Do I send the history?",
+                    "Yes, when using the ChatGPT API, you do.\n",
+                ),
+                message("<b>?</b>", "Use `messages`."),
+            ],
+            msec: 1701645807094,
+        }
+    }
+
+    #[test]
+    fn test_name() {
+        assert_eq!(name(&conversation()), "Yes when using the ChatGPT API");
+    }
+
+    #[test]
+    fn test_export_text() {
+        let text = export(&conversation(), Format::Text);
+        let mut lines = text.lines();
+        assert_eq!(lines.next(), Some("uuid: 8dd3163f"));
+        assert_eq!(lines.next(), Some("name: Yes when using the ChatGPT API"));
+        assert!(lines.next().unwrap().starts_with("timestamp: 2023-12-0"));
+        assert_eq!(
+            lines.collect::<Vec<_>>(),
+            vec![
+                "----",
+                "Yes, when using the ChatGPT API, you do.",
+                "----",
+                "Use `messages`.",
+                "----"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_json() {
+        let value: Value = serde_json::from_str(&export(&conversation(), Format::Json)).unwrap();
+        assert_eq!(value["id"], "8dd3163f");
+        assert_eq!(value["messages"][1]["response"], "Use `messages`.");
+        assert_eq!(value["messages"][0]["run_id"], Value::Null);
+
+        let jsonl = export(&conversation(), Format::Jsonl);
+        assert_eq!(jsonl.lines().count(), 2);
+        for line in jsonl.lines() {
+            let value: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(value["conversation_id"], "8dd3163f");
+        }
+    }
+
+    #[test]
+    fn test_export_markdown_html() {
+        let text = export(&conversation(), Format::Markdown);
+        assert!(text.starts_with("# Yes when using the ChatGPT API\n\n- id: 8dd3163f\n"));
+        assert!(text.contains("\n## Prompt\n\n<b>?</b>\n\n## Response\n\n*Morpha, gpt-4-turbo, "));
+        assert!(text.ends_with("Use `messages`.\n"));
+
+        let html = export(&conversation(), Format::Html);
+        assert!(html.contains("<pre>&lt;b&gt;?&lt;/b&gt;</pre>"));
+        assert!(html.ends_with("</html>\n"));
+    }
+}
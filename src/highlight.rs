@@ -0,0 +1,570 @@
+/// Colors used to highlight code
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// Colors for a dark terminal background
+    Dark,
+    /// Colors for a light terminal background
+    Light,
+    /// No highlighting
+    Off,
+}
+
+impl Theme {
+    /// Select Graphic Rendition parameters for a kind of token, if it is colored
+    fn sgr(&self, token: Token) -> Option<&'static str> {
+        match (self, token) {
+            (Theme::Off, _) | (_, Token::Plain) => None,
+            (Theme::Dark, Token::Keyword) => Some("35"),
+            (Theme::Dark, Token::Type) => Some("33"),
+            (Theme::Dark, Token::String) => Some("32"),
+            (Theme::Dark, Token::Number) => Some("36"),
+            (Theme::Dark, Token::Comment) => Some("2;3"),
+            (Theme::Light, Token::Keyword) => Some("34"),
+            (Theme::Light, Token::Type) => Some("35"),
+            (Theme::Light, Token::String) => Some("31"),
+            (Theme::Light, Token::Number) => Some("36"),
+            (Theme::Light, Token::Comment) => Some("2;3"),
+        }
+    }
+}
+
+/// Kind of text in a line of code
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    Plain,
+    Keyword,
+    Type,
+    String,
+    Number,
+    Comment,
+}
+
+/// Syntax of a language, as much as is needed to highlight it
+pub struct Language {
+    /// Tags naming the language after an opening fence
+    pub names: &'static [&'static str],
+    /// Keywords, separated by spaces
+    keywords: &'static str,
+    /// Names of built-in types, separated by spaces
+    types: &'static str,
+    /// Whether capitalized identifiers are types
+    capitalized_types: bool,
+    /// Whether keywords match regardless of case
+    ignore_case: bool,
+    line_comments: &'static [&'static str],
+    block_comment: Option<(&'static str, &'static str)>,
+    quotes: &'static [char],
+    /// Whether strings in three quotes, which may span lines, begin with `"""` or `'''`
+    triple_quotes: bool,
+    /// Whether raw strings, which may span lines, begin with `r"` or `r#"` and end with `"#`
+    raw_strings: bool,
+    /// Whether `'` begins a char literal or a lifetime, as in Rust
+    char_literals: bool,
+}
+
+const C_COMMENTS: Option<(&str, &str)> = Some(("/*", "*/"));
+
+static LANGUAGES: &[Language] = &[
+    Language {
+        names: &["rust", "rs"],
+        keywords: "\
+            as async await break const continue crate dyn else enum extern false fn for if impl \
+            in let loop match mod move mut pub ref return self Self static struct super trait \
+            true type unsafe use where while",
+        types: "bool char f32 f64 i8 i16 i32 i64 i128 isize str u8 u16 u32 u64 u128 usize",
+        capitalized_types: true,
+        ignore_case: false,
+        line_comments: &["//"],
+        block_comment: C_COMMENTS,
+        quotes: &['"'],
+        triple_quotes: false,
+        raw_strings: true,
+        char_literals: true,
+    },
+    Language {
+        names: &["python", "py"],
+        keywords: "\
+            and as assert async await break class continue def del elif else except False \
+            finally for from global if import in is lambda None nonlocal not or pass raise \
+            return True try while with yield",
+        types: "bool bytes dict float int list object set str tuple",
+        capitalized_types: true,
+        ignore_case: false,
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: true,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["javascript", "js", "jsx", "typescript", "ts", "tsx"],
+        keywords: "\
+            async await break case catch class const continue default delete do else enum export \
+            extends false finally for from function if implements import in instanceof interface \
+            let new null of return switch this throw true try type typeof undefined var void \
+            while yield",
+        types: "any boolean never number string unknown",
+        capitalized_types: true,
+        ignore_case: false,
+        line_comments: &["//"],
+        block_comment: C_COMMENTS,
+        quotes: &['"', '\'', '`'],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["go", "golang"],
+        keywords: "\
+            break case chan const continue default defer else fallthrough false for func go goto \
+            if import interface map nil package range return select struct switch true type var",
+        types: "\
+            bool byte error float32 float64 int int8 int16 int32 int64 rune string uint uint8 \
+            uint16 uint32 uint64",
+        capitalized_types: false,
+        ignore_case: false,
+        line_comments: &["//"],
+        block_comment: C_COMMENTS,
+        quotes: &['"', '\'', '`'],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["c", "h", "cpp", "c++", "cc", "hpp", "cxx"],
+        keywords: "\
+            auto break case class const continue default delete do else enum extern false for \
+            goto if namespace new nullptr private protected public return sizeof static struct \
+            switch template this true typedef typename union using virtual volatile while",
+        types: "bool char double float int long short signed size_t unsigned void",
+        capitalized_types: false,
+        ignore_case: false,
+        line_comments: &["//"],
+        block_comment: C_COMMENTS,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["java", "kotlin", "kt"],
+        keywords: "\
+            abstract break case catch class continue default do else extends false final finally \
+            for fun if implements import interface new null package private protected public \
+            return static super switch this throw throws true try val var when while",
+        types: "boolean byte char double float int long short void",
+        capitalized_types: true,
+        ignore_case: false,
+        line_comments: &["//"],
+        block_comment: C_COMMENTS,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["sh", "bash", "zsh", "shell", "console"],
+        keywords: "\
+            case do done elif else esac exit export fi for function if in local return then \
+            until while",
+        types: "",
+        capitalized_types: false,
+        ignore_case: false,
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["sql", "sqlite", "postgresql", "mysql"],
+        keywords: "\
+            and as asc begin by case commit create delete desc distinct drop else end exists \
+            from group having in index inner insert into is join key left like limit not null \
+            offset on or order outer primary references right select set table then union update \
+            values when where with",
+        types: "blob boolean char date integer int real text timestamp varchar",
+        capitalized_types: false,
+        ignore_case: true,
+        line_comments: &["--"],
+        block_comment: C_COMMENTS,
+        quotes: &['\'', '"'],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["json", "jsonl"],
+        keywords: "false null true",
+        types: "",
+        capitalized_types: false,
+        ignore_case: false,
+        line_comments: &[],
+        block_comment: None,
+        quotes: &['"'],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+    Language {
+        names: &["toml", "yaml", "yml", "ini"],
+        keywords: "false true",
+        types: "",
+        capitalized_types: false,
+        ignore_case: false,
+        line_comments: &["#"],
+        block_comment: None,
+        quotes: &['"', '\''],
+        triple_quotes: false,
+        raw_strings: false,
+        char_literals: false,
+    },
+];
+
+/// The language named by the tag of an opening fence, as in ```rust or ```rust,ignore
+pub fn language(tag: &str) -> Option<&'static Language> {
+    let name = tag
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .next()?
+        .to_ascii_lowercase();
+    LANGUAGES.iter().find(|l| l.names.contains(&name.as_str()))
+}
+
+/// Highlighter of the lines of a code block, in order
+pub struct Highlighter {
+    language: &'static Language,
+    theme: Theme,
+    /// Kind and closing delimiter of a comment or string that continues from a previous line
+    open: Option<(Token, String)>,
+}
+
+impl Highlighter {
+    pub fn new(language: &'static Language, theme: Theme) -> Self {
+        Self {
+            language,
+            theme,
+            open: None,
+        }
+    }
+
+    /// A line of code, without its newline, colored with ANSI escapes
+    pub fn line(&mut self, line: &str) -> String {
+        let mut out = String::new();
+        let mut rest = line;
+        while !rest.is_empty() {
+            let (token, len) = self.token(rest);
+            // the rest of a line the tokenizer cannot split is left plain
+            if len == 0 || !rest.is_char_boundary(len) {
+                self.open = None;
+                out.push_str(rest);
+                break;
+            }
+            let (text, after) = rest.split_at(len);
+            match self.theme.sgr(token) {
+                Some(sgr) => out.push_str(&format!("\x1b[{}m{}\x1b[0m", sgr, text)),
+                None => out.push_str(text),
+            }
+            rest = after;
+        }
+        out
+    }
+
+    /// Kind and length in bytes of the token beginning `text`
+    fn token(&mut self, text: &str) -> (Token, usize) {
+        let language = self.language;
+        if let Some((token, end)) = self.open.take() {
+            return self.delimited(text, 0, token, &end);
+        }
+        if let Some((start, end)) = language.block_comment {
+            if text.starts_with(start) {
+                return self.delimited(text, start.len(), Token::Comment, end);
+            }
+        }
+        if language.line_comments.iter().any(|c| text.starts_with(c)) {
+            return (Token::Comment, text.len());
+        }
+        if language.triple_quotes {
+            if let Some(quotes) = ["\"\"\"", "'''"].into_iter().find(|q| text.starts_with(q)) {
+                return self.delimited(text, quotes.len(), Token::String, quotes);
+            }
+        }
+        if language.raw_strings {
+            if let Some((start, end)) = raw_string(text) {
+                return self.delimited(text, start, Token::String, &end);
+            }
+        }
+
+        let c = text.chars().next().unwrap();
+        if language.char_literals && c == '\'' {
+            return char_literal_len(text);
+        }
+        if language.quotes.contains(&c) {
+            return (Token::String, string_len(text, c));
+        }
+        if c.is_ascii_digit() {
+            let len = text
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '.' || c == '_'))
+                .unwrap_or(text.len());
+            return (Token::Number, len);
+        }
+        if c.is_alphabetic() || c == '_' {
+            let len = text
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .unwrap_or(text.len());
+            return (self.word(&text[..len]), len);
+        }
+        // run of other characters up to the next that may begin a token
+        let len = text
+            .char_indices()
+            .skip(1)
+            .find(|(i, c)| {
+                c.is_alphanumeric()
+                    || *c == '_'
+                    || language.quotes.contains(c)
+                    || (language.char_literals && *c == '\'')
+                    || {
+                        let rest = &text[*i..];
+                        language.line_comments.iter().any(|p| rest.starts_with(p))
+                            || language
+                                .block_comment
+                                .is_some_and(|(s, _)| rest.starts_with(s))
+                    }
+            })
+            .map_or(text.len(), |(i, _)| i);
+        (Token::Plain, len)
+    }
+
+    /// Kind and length of a comment or string whose opening delimiter is `start` bytes long,
+    /// which continues on the next line if `end` does not close it on this one
+    fn delimited(&mut self, text: &str, start: usize, token: Token, end: &str) -> (Token, usize) {
+        match text[start..].find(end) {
+            Some(i) => (token, start + i + end.len()),
+            None => {
+                self.open = Some((token, end.to_string()));
+                (token, text.len())
+            }
+        }
+    }
+
+    fn word(&self, word: &str) -> Token {
+        let language = self.language;
+        let matches = |list: &str| match language.ignore_case {
+            true => list.split(' ').any(|w| w.eq_ignore_ascii_case(word)),
+            false => list.split(' ').any(|w| w == word),
+        };
+        if matches(language.keywords) {
+            Token::Keyword
+        } else if matches(language.types)
+            || (language.capitalized_types && word.starts_with(char::is_uppercase))
+        {
+            Token::Type
+        } else {
+            Token::Plain
+        }
+    }
+}
+
+/// Length in bytes of the string beginning `text`, or the rest of the line if it is unterminated
+fn string_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices().skip(1) {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            c if c == quote => return i + c.len_utf8(),
+            _ => {}
+        }
+    }
+    text.len()
+}
+
+/// Length of the opening delimiter of the raw string beginning `text`, as in `r#"` or `br"`,
+/// and its closing delimiter
+fn raw_string(text: &str) -> Option<(usize, String)> {
+    let rest = text.strip_prefix('b').unwrap_or(text).strip_prefix('r')?;
+    let hashes = rest.len() - rest.trim_start_matches('#').len();
+    rest[hashes..].starts_with('"').then(|| {
+        let start = text.len() - rest.len() + hashes + 1;
+        (start, format!("\"{}", "#".repeat(hashes)))
+    })
+}
+
+/// Kind and length of the char literal or lifetime beginning `text`, as in 'x', '\n' or 'a
+fn char_literal_len(text: &str) -> (Token, usize) {
+    let mut chars = text.char_indices().skip(1);
+    match (chars.next(), chars.next()) {
+        (Some((_, '\\')), Some((i, c))) => {
+            let start = i + c.len_utf8();
+            match text[start..].find('\'') {
+                Some(j) => (Token::String, start + j + 1),
+                None => (Token::Plain, 1),
+            }
+        }
+        (Some(_), Some((i, '\''))) => (Token::String, i + 1),
+        (Some((i, c)), _) if c.is_alphabetic() || c == '_' => {
+            let len = text[i..]
+                .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map_or(text.len(), |j| i + j);
+            (Token::Type, len)
+        }
+        _ => (Token::Plain, 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Highlight `lines` with markers in place of escape sequences
+    fn marked(tag: &str, lines: &[&str]) -> Vec<String> {
+        let mut highlighter = Highlighter::new(language(tag).unwrap(), Theme::Dark);
+        lines
+            .iter()
+            .map(|line| {
+                highlighter
+                    .line(line)
+                    .replace("\x1b[35m", "<k>")
+                    .replace("\x1b[33m", "<t>")
+                    .replace("\x1b[32m", "<s>")
+                    .replace("\x1b[36m", "<n>")
+                    .replace("\x1b[2;3m", "<c>")
+                    .replace("\x1b[0m", "</>")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_language() {
+        assert_eq!(language("rust,ignore").unwrap().names[0], "rust");
+        assert_eq!(language("PY").unwrap().names[0], "python");
+        assert!(language("brainfuck").is_none());
+        assert!(language("").is_none());
+    }
+
+    #[test]
+    fn test_highlight() {
+        assert_eq!(
+            marked("rust", &["let x: u8 = 42; // \"answer\""]),
+            vec!["<k>let</> x: <t>u8</> = <n>42</>; <c>// \"answer\"</>"]
+        );
+        assert_eq!(
+            marked("python", &["print(\"a \\\" # b\")  # done"]),
+            vec!["print(<s>\"a \\\" # b\"</>)  <c># done</>"]
+        );
+        assert_eq!(
+            marked("sql", &["SELECT id FROM t -- all"]),
+            vec!["<k>SELECT</> id <k>FROM</> t <c>-- all</>"]
+        );
+    }
+
+    #[test]
+    fn test_block_comment() {
+        assert_eq!(
+            marked("c", &["int a; /* one", "two */ return a;"]),
+            vec!["<t>int</> a; <c>/* one</>", "<c>two */</> <k>return</> a;"]
+        );
+    }
+
+    #[test]
+    fn test_nested_quotes() {
+        assert_eq!(
+            marked("python", &[r#"s = 'say "hi"' + "it's""#]),
+            vec![r#"s = <s>'say "hi"'</> + <s>"it's"</>"#]
+        );
+        assert_eq!(
+            marked("rust", &[r#"let s = "a /* b */ // c";"#]),
+            vec![r#"<k>let</> s = <s>"a /* b */ // c"</>;"#]
+        );
+    }
+
+    #[test]
+    fn test_raw_string() {
+        assert_eq!(
+            marked("rust", &[r##"let s = r#"a "quoted" \ word"#;"##]),
+            vec![r##"<k>let</> s = <s>r#"a "quoted" \ word"#</>;"##]
+        );
+        assert_eq!(
+            marked(
+                "rust",
+                &[r###"f(br"x", r##"one "#"###, r###"two"## + 1)"###]
+            ),
+            vec![
+                r###"f(<s>br"x"</>, <s>r##"one "#</>"###,
+                r###"<s>two"##</> + <n>1</>)"###
+            ]
+        );
+        // a raw identifier is not a string
+        assert_eq!(marked("rust", &["r#type"]), vec!["r#<k>type</>"]);
+    }
+
+    #[test]
+    fn test_triple_quotes() {
+        assert_eq!(
+            marked(
+                "python",
+                &[r#"doc = """one "two""#, r#"it's # three""" # done"#]
+            ),
+            vec![
+                r#"doc = <s>"""one "two"</>"#,
+                r#"<s>it's # three"""</> <c># done</>"#
+            ]
+        );
+        assert_eq!(
+            marked("python", &["x = '''a''' + ''"]),
+            vec!["x = <s>'''a'''</> + <s>''</>"]
+        );
+    }
+
+    #[test]
+    fn test_lifetimes_and_chars() {
+        assert_eq!(
+            marked("rust", &[r"fn f<'a>(s: &'a str) -> char { '\'' }"]),
+            vec![r"<k>fn</> f<<t>'a</>>(s: &<t>'a</> <t>str</>) -> <t>char</> { <s>'\''</> }"]
+        );
+        assert_eq!(
+            marked("rust", &[r"['x', 'é', '\n', '\u{1F600}'] 'static"]),
+            vec![r"[<s>'x'</>, <s>'é'</>, <s>'\n'</>, <s>'\u{1F600}'</>] <t>'static</>"]
+        );
+    }
+
+    #[test]
+    fn test_malformed_code() {
+        // stray and unterminated delimiters never lose or reorder text
+        let lines = [
+            "'",
+            r"'\",
+            "r#",
+            r##"br##"x"#"##,
+            r#"""""#,
+            "'é",
+            "/*",
+            "→ '→'",
+        ];
+        for tag in ["rust", "python", "c"] {
+            for theme in [Theme::Dark, Theme::Off] {
+                let mut highlighter = Highlighter::new(language(tag).unwrap(), theme);
+                for line in lines {
+                    let plain: String = highlighter
+                        .line(line)
+                        .split('\x1b')
+                        .enumerate()
+                        .map(|(i, part)| match i {
+                            0 => part,
+                            _ => &part[part.find('m').unwrap() + 1..],
+                        })
+                        .collect();
+                    assert_eq!(plain, line);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_theme_off() {
+        let mut highlighter = Highlighter::new(language("rust").unwrap(), Theme::Off);
+        assert_eq!(highlighter.line("fn main() {}"), "fn main() {}");
+    }
+}
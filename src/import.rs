@@ -0,0 +1,393 @@
+use crate::conversation::{Conversation, Message};
+use crate::error::MorphaError;
+
+use rusqlite::{Connection, OptionalExtension};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Delimiter around the responses of a plain text export
+const TEXT_DELIMITER: &str = "----";
+
+/// Name recorded as the assistant of messages from a ChatGPT export
+const CHATGPT_ASSISTANT: &str = "ChatGPT";
+
+/// Conversations read from a ChatGPT data export (`.json`) or a plain text export
+pub fn read(path: &Path) -> Result<Vec<Conversation>, MorphaError> {
+    let text = std::fs::read_to_string(path)?;
+    let conversations = match path.extension().is_some_and(|e| e == "json") {
+        true => chatgpt(&text),
+        false => self::text(&text).map(|c| vec![c]),
+    };
+    conversations.map_err(|e| MorphaError::Import(format!("{}: {}", path.display(), e)))
+}
+
+/// Conversations of a ChatGPT data export's `conversations.json`
+///
+/// Each conversation is a tree of messages, of which the branch ending at the current node is
+/// the one last shown. Prompts are paired with the responses that follow them, and system and
+/// tool messages are left out.
+pub fn chatgpt(text: &str) -> Result<Vec<Conversation>, String> {
+    let value: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let list = value
+        .as_array()
+        .ok_or("expected an array of conversations")?;
+    let mut conversations = Vec::new();
+    for (i, item) in list.iter().enumerate() {
+        let id = item["conversation_id"]
+            .as_str()
+            .or_else(|| item["id"].as_str())
+            .ok_or_else(|| format!("conversation {} has no id", i + 1))?;
+        let msec = seconds_to_msec(&item["create_time"]).unwrap_or_default();
+        let mut messages: Vec<Message> = Vec::new();
+        let mut pending: Option<Message> = None;
+        for node in branch(&item["mapping"], item["current_node"].as_str()) {
+            let message = &node["message"];
+            let content = content_text(&message["content"]);
+            if content.is_empty() {
+                continue;
+            }
+            let time = seconds_to_msec(&message["create_time"]).unwrap_or(msec);
+            match message["author"]["role"].as_str() {
+                Some("user") => {
+                    messages.extend(pending.take());
+                    pending = Some(Message {
+                        conversation_id: id.to_string(),
+                        msec: time,
+                        prompt: content,
+                        ..Default::default()
+                    });
+                }
+                Some("assistant") => {
+                    let reply = pending.get_or_insert_with(|| Message {
+                        conversation_id: id.to_string(),
+                        msec: time,
+                        ..Default::default()
+                    });
+                    if !reply.response.is_empty() {
+                        reply.response.push_str("\n\n");
+                    }
+                    reply.response.push_str(&content);
+                    reply.msec = time;
+                    reply.assistant = Some(CHATGPT_ASSISTANT.to_string());
+                    if let Some(model) = message["metadata"]["model_slug"].as_str() {
+                        reply.model = Some(model.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+        messages.extend(pending);
+        conversations.push(Conversation {
+            id: id.to_string(),
+            messages,
+            msec,
+        });
+    }
+    Ok(conversations)
+}
+
+/// Nodes of the branch of a conversation's message tree ending at `current`, from the root
+///
+/// Without a current node the latest reply to each message is followed from the root.
+fn branch<'a>(mapping: &'a Value, current: Option<&str>) -> Vec<&'a Value> {
+    let Some(nodes) = mapping.as_object() else {
+        return Vec::new();
+    };
+    let mut branch = Vec::new();
+    match current {
+        Some(current) => {
+            let mut node = nodes.get(current);
+            while let Some(n) = node {
+                branch.push(n);
+                // a malformed tree could loop back on itself
+                if branch.len() > nodes.len() {
+                    break;
+                }
+                node = n["parent"].as_str().and_then(|p| nodes.get(p));
+            }
+            branch.reverse();
+        }
+        None => {
+            let mut node = nodes.values().find(|n| n["parent"].is_null());
+            while let Some(n) = node {
+                branch.push(n);
+                if branch.len() > nodes.len() {
+                    break;
+                }
+                node = n["children"]
+                    .as_array()
+                    .and_then(|c| c.last())
+                    .and_then(Value::as_str)
+                    .and_then(|c| nodes.get(c));
+            }
+        }
+    }
+    branch
+}
+
+/// Text parts of a message's content, leaving out images and other attachments
+fn content_text(content: &Value) -> String {
+    let parts = content["parts"].as_array().map_or(&[][..], Vec::as_slice);
+    parts
+        .iter()
+        .filter_map(Value::as_str)
+        .collect::<Vec<_>>()
+        .join("\n")
+        .trim()
+        .to_string()
+}
+
+fn seconds_to_msec(value: &Value) -> Option<i64> {
+    value.as_f64().map(|seconds| (seconds * 1000.0) as i64)
+}
+
+/// A conversation exported as plain text, with a uuid, name and timestamp header followed by
+/// responses between `----` lines, as in `design/*.txt`
+pub fn text(text: &str) -> Result<Conversation, String> {
+    let mut lines = text.lines();
+    let mut id = None;
+    let mut msec = None;
+    for (number, line) in lines.by_ref().enumerate() {
+        if line.trim_end() == TEXT_DELIMITER {
+            break;
+        }
+        let (key, value) = line
+            .split_once(':')
+            .ok_or_else(|| format!("{}: expected <key>: <value>", number + 1))?;
+        let value = value.trim();
+        match key.trim() {
+            "uuid" => id = Some(value.to_string()),
+            "timestamp" => {
+                let time = chrono::DateTime::parse_from_rfc3339(value)
+                    .map_err(|e| format!("{}: invalid timestamp: {}", number + 1, e))?;
+                msec = Some(time.timestamp_millis());
+            }
+            // the name is derived from the first response again when exported
+            _ => {}
+        }
+    }
+    let id = id.ok_or("missing uuid")?;
+    let msec = msec.ok_or("missing timestamp")?;
+
+    let mut messages = Vec::new();
+    let mut response = String::new();
+    for line in lines {
+        if line.trim_end() != TEXT_DELIMITER {
+            response.push_str(line);
+            response.push('\n');
+            continue;
+        }
+        messages.push(Message {
+            conversation_id: id.clone(),
+            msec,
+            response: std::mem::take(&mut response).trim_end().to_string(),
+            ..Default::default()
+        });
+    }
+    if !response.trim().is_empty() {
+        return Err("response does not end with ----".to_string());
+    }
+    Ok(Conversation { id, messages, msec })
+}
+
+/// Counts of what an import added to the archive
+#[derive(Debug, Default, PartialEq)]
+pub struct Summary {
+    /// Conversations not archived before
+    pub conversations: usize,
+    /// Messages added, whether to new or archived conversations
+    pub messages: usize,
+    /// Conversations already archived that gained messages
+    pub updated: usize,
+    /// Conversations already archived with every message
+    pub skipped: usize,
+}
+
+/// Archive conversations and messages not already archived, in one transaction
+///
+/// A message is already archived if its conversation has one with the same time, prompt and
+/// response, so importing a newer export adds the messages since the last import. Messages are
+/// added to the full text search index as they are written.
+pub fn archive(db: &Connection, conversations: &[Conversation]) -> Result<Summary, MorphaError> {
+    let tx = db.unchecked_transaction()?;
+    let mut summary = Summary::default();
+    for conversation in conversations {
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM conversations WHERE id = ?1",
+                [&conversation.id],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        if !exists {
+            conversation.write_to_database(&tx)?;
+            for message in &conversation.messages {
+                message.write_to_database(&tx)?;
+            }
+            summary.conversations += 1;
+            summary.messages += conversation.messages.len();
+            continue;
+        }
+
+        let mut archived = archived_messages(&tx, &conversation.id)?;
+        let mut added = 0;
+        for message in &conversation.messages {
+            let key = (
+                message.msec,
+                message.prompt.clone(),
+                message.response.clone(),
+            );
+            match archived.get_mut(&key) {
+                // a conversation may repeat a message, so each archived one matches once
+                Some(count) if *count > 0 => *count -= 1,
+                _ => {
+                    message.write_to_database(&tx)?;
+                    added += 1;
+                }
+            }
+        }
+        match added {
+            0 => summary.skipped += 1,
+            _ => summary.updated += 1,
+        }
+        summary.messages += added;
+    }
+    tx.commit()?;
+    Ok(summary)
+}
+
+/// Number of messages archived in a conversation with each time, prompt and response
+fn archived_messages(
+    db: &Connection,
+    conversation_id: &str,
+) -> rusqlite::Result<HashMap<(i64, String, String), usize>> {
+    let mut stmt =
+        db.prepare("SELECT msec, prompt, response FROM messages WHERE conversation_id = ?1")?;
+    let rows = stmt.query_map([conversation_id], |row| {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    })?;
+    let mut archived = HashMap::new();
+    for key in rows {
+        *archived.entry(key?).or_insert(0) += 1;
+    }
+    Ok(archived)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database;
+
+    const CHATGPT: &str = r#"[{
+        "title": "Boiling",
+        "create_time": 1701645807.094,
+        "current_node": "c",
+        "conversation_id": "8dd3163f",
+        "mapping": {
+            "root": {"id": "root", "message": null, "parent": null, "children": ["s"]},
+            "s": {"id": "s", "parent": "root", "children": ["a"], "message": {
+                "author": {"role": "system"}, "content": {"parts": [""]}}},
+            "a": {"id": "a", "parent": "s", "children": ["b", "x"], "message": {
+                "author": {"role": "user"}, "create_time": 1701645808.0,
+                "content": {"content_type": "text", "parts": ["Why does water boil?"]}}},
+            "x": {"id": "x", "parent": "a", "children": [], "message": {
+                "author": {"role": "assistant"}, "content": {"parts": ["A discarded answer."]}}},
+            "b": {"id": "b", "parent": "a", "children": ["c"], "message": {
+                "author": {"role": "assistant"}, "create_time": 1701645810.5,
+                "metadata": {"model_slug": "gpt-4"},
+                "content": {"parts": ["Heat.", {"asset_pointer": "image"}]}}},
+            "c": {"id": "c", "parent": "b", "children": [], "message": {
+                "author": {"role": "user"}, "content": {"parts": ["Thanks"]}}}
+        }
+    }]"#;
+
+    #[test]
+    fn test_chatgpt() {
+        let conversations = chatgpt(CHATGPT).unwrap();
+        assert_eq!(conversations.len(), 1);
+        let conversation = &conversations[0];
+        assert_eq!(conversation.id, "8dd3163f");
+        assert_eq!(conversation.msec, 1701645807094);
+        let messages: Vec<(&str, &str)> = conversation
+            .messages
+            .iter()
+            .map(|m| (m.prompt.as_str(), m.response.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![("Why does water boil?", "Heat."), ("Thanks", "")]
+        );
+        assert_eq!(conversation.messages[0].model.as_deref(), Some("gpt-4"));
+        assert_eq!(conversation.messages[0].msec, 1701645810500);
+
+        assert!(chatgpt("{}").is_err());
+        assert!(chatgpt("[{\"mapping\": {}}]").is_err());
+    }
+
+    #[test]
+    fn test_text() {
+        let conversation = text(include_str!("../design/gpt_response_method.txt")).unwrap();
+        assert_eq!(conversation.id, "8dd3163f-b8f4-4075-a082-49577a62343f");
+        assert_eq!(conversation.msec, 1701645807094);
+        assert_eq!(conversation.messages.len(), 1);
+        let response = &conversation.messages[0].response;
+        assert!(response.starts_with("Yes, when using the ChatGPT API"));
+        assert!(response.ends_with("generate responses."));
+
+        assert_eq!(text("name: x\n----\n").unwrap_err(), "missing uuid");
+        assert_eq!(
+            text("uuid: x\ntimestamp: 2023-12-03T15:23:27-08:00\n----\nunfinished").unwrap_err(),
+            "response does not end with ----"
+        );
+    }
+
+    #[test]
+    fn test_archive() {
+        let db = database::open_in_memory().unwrap();
+        let conversations = chatgpt(CHATGPT).unwrap();
+        let summary = archive(&db, &conversations).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                conversations: 1,
+                messages: 2,
+                ..Default::default()
+            }
+        );
+        // importing again adds nothing
+        let summary = archive(&db, &conversations).unwrap();
+        assert_eq!(summary.skipped, 1);
+        assert_eq!(summary.messages, 0);
+
+        // a newer export adds the messages since
+        let mut conversations = chatgpt(CHATGPT).unwrap();
+        conversations[0].messages.push(Message {
+            conversation_id: "8dd3163f".to_string(),
+            msec: 1701645900000,
+            prompt: "And at altitude?".to_string(),
+            response: "Lower.".to_string(),
+            ..Default::default()
+        });
+        let summary = archive(&db, &conversations).unwrap();
+        assert_eq!(
+            summary,
+            Summary {
+                messages: 1,
+                updated: 1,
+                ..Default::default()
+            }
+        );
+        let messages = crate::conversation::read_conversation(&db, "8dd3163f")
+            .unwrap()
+            .unwrap()
+            .messages;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[2].prompt, "And at altitude?");
+
+        let results = database::search(&db, &database::search_query(&["boil"])).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].message.conversation_id, "8dd3163f");
+    }
+}
@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+/// A request from the user to stop what the session is doing
+///
+/// Only a Ctrl-C pressed while the session waits on the interrupt counts, so one pressed while
+/// the session is busy elsewhere does not end the next wait.
+#[derive(Clone, Default)]
+pub struct Interrupt {
+    /// Whether Ctrl-C triggers the interrupt
+    ctrl_c: bool,
+    triggered: Arc<Notify>,
+}
+
+impl Interrupt {
+    /// An interrupt that is only ever triggered by calling `trigger`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An interrupt triggered by Ctrl-C instead of the process being terminated
+    ///
+    /// Must be called within the runtime, so the handler replaces termination from the start.
+    pub fn ctrl_c() -> std::io::Result<Self> {
+        // the handler stays installed once the first listener is created
+        #[cfg(unix)]
+        drop(tokio::signal::unix::signal(
+            tokio::signal::unix::SignalKind::interrupt(),
+        )?);
+        Ok(Self {
+            ctrl_c: true,
+            triggered: Arc::default(),
+        })
+    }
+
+    /// Trigger the interrupt, ending the current wait or else the next one
+    pub fn trigger(&self) {
+        self.triggered.notify_one();
+    }
+
+    /// Wait until the interrupt is triggered
+    pub async fn wait(&self) {
+        if !self.ctrl_c {
+            return self.triggered.notified().await;
+        }
+        tokio::select! {
+            _ = self.triggered.notified() => {}
+            result = tokio::signal::ctrl_c() => {
+                if result.is_err() {
+                    // without a handler Ctrl-C can only be waited on through `trigger`
+                    self.triggered.notified().await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_ctrl_c() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let interrupt = Interrupt::ctrl_c().unwrap();
+            // pressed while the session is busy, which the next wait ignores
+            unsafe { libc::raise(libc::SIGINT) };
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let wait = tokio::time::timeout(Duration::from_millis(100), interrupt.wait());
+            assert!(wait.await.is_err());
+
+            let waiting = tokio::time::timeout(Duration::from_secs(5), interrupt.wait());
+            let press = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                unsafe { libc::raise(libc::SIGINT) };
+            };
+            let (waited, ()) = tokio::join!(waiting, press);
+            assert!(waited.is_ok());
+        });
+    }
+}
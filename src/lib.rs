@@ -1,4 +1,19 @@
+pub mod attachment;
+pub mod backend;
+pub mod batch;
+pub mod citation;
+pub mod commands;
+pub mod config;
 pub mod conversation;
 pub mod database;
+pub mod error;
+pub mod explain;
+pub mod export;
+pub mod highlight;
+pub mod import;
+pub mod interrupt;
+pub mod markdown;
 pub mod personality;
+pub mod session;
 pub mod status;
+pub mod template;
@@ -1,19 +1,39 @@
-use morpha::conversation::{Conversation, Message};
+use morpha::attachment::{self, Attachment};
+use morpha::backend::{self, Kind};
+use morpha::batch;
+use morpha::commands::{self, CommandError};
+use morpha::config::{self, ConfigFile, Settings};
+use morpha::conversation;
 use morpha::database;
-use morpha::personality::Mode::{Interactive, NonInteractive};
-use morpha::personality::Personality;
-use morpha::status::Status;
+use morpha::error::MorphaError;
+use morpha::export;
+use morpha::highlight::Theme;
+use morpha::import;
+use morpha::interrupt::Interrupt;
+use morpha::markdown::Format;
+use morpha::personality::Mode::Interactive;
+use morpha::personality::{self, Personality};
+use morpha::session::Session;
+use morpha::template::{self, Environment};
 
-use async_openai::{
-    types::{
-        CreateAssistantRequestArgs, CreateMessageRequestArgs, CreateRunRequestArgs,
-        CreateThreadRequestArgs, MessageContent, RunStatus,
-    },
-    Client,
-};
-use clap::Parser;
+use clap::{builder::RangedU64ValueParser, Parser, Subcommand};
+use serde_json::{json, Value};
 use std::error::Error;
-use std::io::{stdin, IsTerminal, Read};
+use std::io::{sink, stdin, stdout, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::BufReader;
+
+/// Exit status of a command that failed
+const EXIT_FAILURE: i32 = 1;
+
+/// Exit status when an archived conversation or message, or any search result, is not found
+const EXIT_NOT_FOUND: i32 = 3;
+
+/// Exit status when the API fails to respond
+const EXIT_API: i32 = 4;
+
+/// Exit status of a session ended with Ctrl-C, as for a process terminated by SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
 
 const CLAP_HELP: &str = r#"{name} version: {version}
 {author}
@@ -26,254 +46,558 @@ build: {before-help}{usage-heading} {usage}
 #[command(author, version = env!("CARGO_PKG_VERSION"), before_help = env!("GIT_HASH"))]
 #[command(help_template = CLAP_HELP)]
 struct Config {
-    /// OpenAI model name
-    #[arg(long, default_value = "gpt-4-turbo")]
-    model: String,
-    /// SQLite database path
-    #[arg(long, required(false), default_value = "")]
-    db_path: String,
+    /// Configuration file [default: ~/.config/morpha/config.toml]
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Profile of the configuration file to use
+    #[arg(long, env = "MORPHA_PROFILE")]
+    profile_name: Option<String>,
+    /// OpenAI model name [default: gpt-4-turbo]
+    #[arg(long)]
+    model: Option<String>,
+    /// Base URL of an OpenAI-compatible API, such as a local Ollama or llama.cpp server
+    #[arg(long, env = "OPENAI_API_BASE")]
+    api_base: Option<String>,
+    /// Seconds to wait for a response, or for the next part of one, before giving up [default: 120]
+    #[arg(long)]
+    timeout: Option<u64>,
+    /// Times to retry a request failing with a rate limit or transient error [default: 3]
+    #[arg(long)]
+    retries: Option<u32>,
+    /// API used to obtain responses [default: chat]
+    #[arg(long, value_enum)]
+    backend: Option<Kind>,
+    /// SQLite database path [default: ~/.morpha.sqlite3]
+    #[arg(long)]
+    db_path: Option<String>,
     #[arg(long, default_value_t = false)]
     /// Do not archive conversation in database
     no_archive: bool,
-    /// File path containing assistant instructions
-    #[arg(long, required(false), default_value = "")]
-    profile: String,
-    /// Print output raw without line wrapping
-    #[arg(long, default_value_t = false)]
+    /// File path containing assistant instructions [default: ~/.morpha_profile]
+    #[arg(long)]
+    profile: Option<String>,
+    /// Personality from the personalities directory to begin with
+    #[arg(long)]
+    persona: Option<String>,
+    /// Directory of personality files [default: ~/.config/morpha/personalities]
+    #[arg(long)]
+    personalities: Option<String>,
+    /// Characters per line of wrapped output, with 0 for no wrapping [default: 80]
+    #[arg(long)]
+    wrap: Option<usize>,
+    /// Print responses exactly as received, without rendering Markdown or wrapping lines
+    #[arg(long, default_value_t = false, conflicts_with = "wrap")]
     raw: bool,
+    /// Colors highlighting fenced code blocks in a terminal [default: dark]
+    #[arg(long, value_enum)]
+    theme: Option<Theme>,
+    /// Print only the code blocks of responses, as to pipe a generated snippet into a file
+    #[arg(long, default_value_t = false)]
+    extract_code: bool,
+    /// Print the output of subcommands as JSON
+    #[arg(long, global = true, default_value_t = false)]
+    json: bool,
+    /// Resume an archived conversation by id or unambiguous prefix
+    #[arg(long)]
+    resume: Option<String>,
+    #[command(subcommand)]
+    command: Option<Commands>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    let mut config = Config::parse();
-
-    let home = std::env::var("HOME")?;
-    if config.db_path.is_empty() {
-        config.db_path = format!("{}/.morpha.sqlite3", home);
-    }
-    if config.profile.is_empty() {
-        config.profile = format!("{}/.morpha_profile", home);
+impl Config {
+    /// Settings given on the command line, overriding those of the configuration file
+    fn options(&self) -> config::Options {
+        config::Options {
+            model: self.model.clone(),
+            backend: self.backend,
+            database: self.db_path.clone(),
+            personality: self.profile.clone(),
+            personalities: self.personalities.clone(),
+            persona: self.persona.clone(),
+            wrap: if self.raw { Some(0) } else { self.wrap },
+            theme: self.theme,
+            api_base: self.api_base.clone(),
+            timeout: self.timeout,
+            retries: self.retries,
+        }
     }
-    let personality_profile = std::fs::read_to_string(config.profile)?;
-    let mut personality = Personality::new("Morpha", &personality_profile);
-    if config.raw {
-        personality.max_chars = None;
+
+    /// Merge the configuration file, the selected profile and the command line
+    fn settings(&self, home: &Path) -> Result<Settings, Box<dyn Error>> {
+        let file = match &self.config {
+            Some(path) if !path.exists() => {
+                return Err(format!("configuration file not found: {}", path.display()).into())
+            }
+            Some(path) => ConfigFile::read(path)?,
+            None => ConfigFile::read(&config::default_path(home))?,
+        };
+        let settings =
+            Settings::resolve(&file, self.profile_name.as_deref(), self.options(), home)?;
+        Ok(settings)
     }
-    let mut status = Status::new();
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Send one prompt and print the response, archiving the exchange
+    ///
+    /// Standard input is only read for the prompt, so an inherited one never holds morpha up.
+    Ask {
+        /// Prompt, or - to read it from standard input as when neither it nor a prompt file is
+        /// given and input is piped
+        prompt: Vec<String>,
+        /// File containing the prompt, or - for standard input
+        #[arg(long, conflicts_with = "prompt")]
+        prompt_file: Option<PathBuf>,
+        /// Text file to attach to the prompt, or - for standard input, which may be given more
+        /// than once
+        #[arg(long = "file", short)]
+        files: Vec<PathBuf>,
+    },
+    /// Send the prompts of a script in order in one conversation, archiving the exchanges
+    ///
+    /// The script has a prompt on each line, or a JSON object on each line with a "prompt" and
+    /// optionally an "id", a "persona" to switch to and "files" to attach.
+    Batch {
+        /// Script of prompts, or - for standard input
+        script: PathBuf,
+        /// File to write a JSON object for each turn to, instead of printing the responses
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Search archived messages, best matches first
+    Search {
+        /// Terms that must all match; quote a phrase to match it exactly and end a term with *
+        /// to match by prefix
+        #[arg(required = true)]
+        terms: Vec<String>,
+    },
+    /// List the models offered by the API
+    Models,
+    /// List archived conversations or messages
+    List {
+        #[command(subcommand)]
+        listing: Listing,
+    },
+    /// Print an archived conversation as Markdown
+    Show {
+        /// Conversation id or unambiguous prefix
+        conversation: String,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Work with personality files
+    Persona {
+        #[command(subcommand)]
+        action: PersonaAction,
+    },
+    /// Write archived conversations to files, one per conversation
+    Export {
+        /// Conversation id or unambiguous prefix
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        conversation: Option<String>,
+        /// Export every archived conversation
+        #[arg(long, default_value_t = false)]
+        all: bool,
+        /// Format of the exported files
+        #[arg(long, value_enum, default_value_t = export::Format::Markdown)]
+        format: export::Format,
+        /// Directory the files are written to, or - for standard output
+        #[arg(long, default_value = ".")]
+        output: PathBuf,
+    },
+    /// Archive conversations from a ChatGPT export's conversations.json or plain text exports
+    Import {
+        /// Files to import, skipping conversations already archived
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the configuration in effect after merging the file, profile and command line
+    Show,
+}
 
-    let client = Client::new();
-    let query = [("limit", "1")]; //limit the list responses to 1 message
-    let thread_request = CreateThreadRequestArgs::default().build()?;
-    let thread = client.threads().create(thread_request).await?;
+#[derive(Subcommand)]
+enum PersonaAction {
+    /// Print the instructions of a personality file with its templates expanded
+    Render {
+        /// Personality file
+        file: PathBuf,
+    },
+}
 
-    let assistant_request = CreateAssistantRequestArgs::default()
-        .name(&personality.name)
-        .instructions(&personality.instructions)
-        .model(&config.model)
-        .build()?;
+#[derive(Subcommand)]
+enum Listing {
+    /// List conversations, most recent first
+    Conversations {
+        /// Page of results to show
+        #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        page: usize,
+    },
+    /// List the messages of a conversation
+    Messages {
+        /// Conversation id or unambiguous prefix
+        conversation: String,
+        /// Page of results to show
+        #[arg(long, default_value_t = 1, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+        page: usize,
+    },
+}
 
-    let assistant = client.assistants().create(assistant_request).await?;
-    let assistant_id = assistant.id;
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run(Config::parse()).await {
+        eprintln!("Error: {}", e);
+        std::process::exit(exit_code(e.as_ref()));
+    }
+}
 
-    // Open database
-    let db = database::open_database(&config.db_path)?;
+async fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let home = PathBuf::from(std::env::var("HOME")?);
+    let settings = config.settings(&home)?;
 
-    // Create conversation
-    let conversation = Conversation {
-        id: assistant_id.clone(),
-        messages: Vec::new(),
-        msec: database::current_msec(),
-    };
+    // run a non-interactive subcommand and exit
+    if let Some(command) = &config.command {
+        return run_subcommand(command, &config, &settings).await;
+    }
 
+    let mut session = new_session(&config, &settings)?;
     // Determine whether input has been piped to stdin or an interactive terminal is present
     if stdin().is_terminal() {
-        personality.mode = Interactive;
-        status.silent = false;
-
-        // Initial greeting
-        personality.speak("How may I assist you?");
-        status.print("\n");
+        session.personality.mode = Interactive;
+        session.status.silent = false;
     }
 
-    // MAIN LOOP
-    let mut first_run = true;
-    let mut empty_commands = 0;
-    'main: loop {
-        // show data prompt read user input
-        status.print("> ");
-        let mut input = String::new();
-
-        // in the case of non-interactive session, read all lines from standard input
-        match personality.mode {
-            Interactive => {
-                stdin().read_line(&mut input).unwrap();
-            }
-            NonInteractive => {
-                stdin().read_to_string(&mut input).unwrap();
-            }
-        }
-
-        // ignore empty line and print exit instructions
-        input = input.trim().to_string();
-        if input.is_empty() {
-            empty_commands += 1;
-            // be nice
-            if empty_commands >= 2 {
-                status.print("/q, /quit, or /exit to leave application\n");
-            }
-            continue;
+    let result = match session.start(config.resume.as_deref()).await {
+        Ok(()) => {
+            let mut input = BufReader::new(tokio::io::stdin());
+            session.run(&mut input, &mut stdout()).await
         }
-        empty_commands = 0; // reset
-        status.print("\n"); // I like readability
+        Err(e) => Err(e),
+    };
 
-        // process custom commands
-        if input.starts_with('/') {
-            match input.as_str() {
-                "/q" => break,
-                "/quit" => break,
-                "/exit" => break,
-                _ => (),
-            }
-            run_command(&input)?;
-            continue;
+    // release remote resources however the session ended
+    let closed = session.close().await;
+    match result {
+        // a pending read of standard input cannot be cancelled, so do not wait on it
+        Err(MorphaError::Interrupted) => {
+            closed?;
+            std::process::exit(EXIT_INTERRUPTED);
         }
+        result => result?,
+    }
+    closed?;
 
-        //create a message for the thread
-        let message = CreateMessageRequestArgs::default()
-            .content(input.clone())
-            .build()?;
-
-        //attach message to the thread
-        let _message_obj = client
-            .threads()
-            .messages(&thread.id)
-            .create(message)
-            .await?;
-
-        //create a run for the thread
-        let run_request = CreateRunRequestArgs::default()
-            .assistant_id(&assistant_id)
-            .build()?;
-        let run = client
-            .threads()
-            .runs(&thread.id)
-            .create(run_request)
-            .await?;
-
-        //wait for the run to complete
-        let mut awaiting_response = true;
-        let mut status_previous: Option<RunStatus> = None;
-        while awaiting_response {
-            let run = client.threads().runs(&thread.id).retrieve(&run.id).await?;
-
-            // periodically check status
-            match run.status {
-                RunStatus::Completed => {
-                    awaiting_response = false;
-
-                    let response = client.threads().messages(&thread.id).list(&query).await?;
-                    let message_id = response.data.first().unwrap().id.clone();
-                    // get the message, content from the response
-                    let message = client
-                        .threads()
-                        .messages(&thread.id)
-                        .retrieve(&message_id)
-                        .await?;
-                    let content = message.content.first().unwrap();
-                    let text = match content {
-                        MessageContent::Text(text) => text.text.value.clone(),
-                        MessageContent::ImageFile(_) => {
-                            panic!("images are not supported in the terminal")
-                        }
-                        MessageContent::ImageUrl(_) => {
-                            panic!("image urls are not supported in the terminal")
-                        }
-                        MessageContent::Refusal(_) => {
-                            panic!("response generated a refusal")
-                        }
-                    };
+    Ok(())
+}
 
-                    // print the response after clearing the status line
-                    status.clear_line();
-                    personality.speak(&text);
-                    status.print("\n"); // I really like readability
+/// Exit status telling a script why a command failed
+fn exit_code(e: &(dyn Error + 'static)) -> i32 {
+    if let Some(CommandError::NotFound(_)) = e.downcast_ref() {
+        return EXIT_NOT_FOUND;
+    }
+    match e.downcast_ref() {
+        Some(MorphaError::Command(e)) => exit_code(e.as_ref()),
+        Some(
+            MorphaError::Api(_)
+            | MorphaError::Timeout(_)
+            | MorphaError::Run { .. }
+            | MorphaError::Refusal(_)
+            | MorphaError::StreamEnded,
+        ) => EXIT_API,
+        Some(MorphaError::Cancelled | MorphaError::Interrupted) => EXIT_INTERRUPTED,
+        _ => EXIT_FAILURE,
+    }
+}
 
-                    // Write the conversation only after valid input and response has been obtained.
-                    // Otherwise, we will have empty conversations when user input is cancelled.
-                    if first_run && !config.no_archive {
-                        conversation.write_to_database(&db)?;
-                    }
+/// Create a session with the personality, archive and backend of the settings
+fn new_session(config: &Config, settings: &Settings) -> Result<Session, Box<dyn Error>> {
+    let (mut personality, dir) = match &settings.persona {
+        Some(name) => (
+            personality::find(&settings.personalities, name)?,
+            settings.personalities.as_path(),
+        ),
+        None => (
+            read_personality(&settings.personality)?,
+            settings.personality.parent().unwrap_or(Path::new(".")),
+        ),
+    };
+    personality.instructions =
+        template::render(&personality.instructions, &Environment::current(), dir)?;
+    personality.max_chars = settings.wrap;
+    personality.theme = settings.theme;
+    personality.format = match config.raw {
+        true => Format::Raw,
+        false if stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none() => Format::Styled,
+        false => Format::Plain,
+    };
 
-                    // Write the prompt and response to database
-                    if !config.no_archive {
-                        let msg = Message {
-                            conversation_id: conversation.id.clone(),
-                            msec: database::current_msec(),
-                            prompt: input.clone(),
-                            response: text.clone(),
-                        };
-                        msg.write_to_database(&db)?;
-                    }
+    let db = database::open_database(&settings.database.to_string_lossy())?;
+    let backend = backend::new(
+        settings.backend,
+        backend::client(settings.api_base.as_deref(), &settings.policy),
+        &settings.model,
+        settings.policy,
+    );
+    let mut session = Session::new(personality, db, backend);
+    session.archive = !config.no_archive;
+    session.extract_code = config.extract_code;
+    session.personalities = Some(settings.personalities.clone());
+    session.interrupt = Interrupt::ctrl_c()?;
+    Ok(session)
+}
 
-                    // exit if one response is requested
-                    if let NonInteractive = personality.mode {
-                        break 'main;
-                    }
-                }
-                RunStatus::Incomplete => {
-                    panic!("Incomplete run status");
-                }
-                RunStatus::Failed => {
-                    awaiting_response = false;
-                    status.print(&format!("--- Run Failed: {:#?}", run));
-                }
-                RunStatus::Queued => {
-                    status.print("--- Run Queued");
+/// Run a non-interactive subcommand
+async fn run_subcommand(
+    command: &Commands,
+    config: &Config,
+    settings: &Settings,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = stdout();
+    let db = || database::open_database(&settings.database.to_string_lossy());
+    match command {
+        Commands::Ask {
+            prompt,
+            prompt_file,
+            files,
+        } => {
+            let attach_stdin = files.iter().any(|f| f == Path::new("-"));
+            let from_stdin = match prompt_file {
+                Some(path) => path == Path::new("-"),
+                None if prompt.is_empty() => !stdin().is_terminal() && !attach_stdin,
+                None => prompt == &["-"],
+            };
+            if from_stdin && attach_stdin {
+                return Err("standard input cannot be both the prompt and a file".into());
+            }
+            let prompt = match prompt_file {
+                _ if from_stdin => {
+                    let mut text = String::new();
+                    stdin().read_to_string(&mut text)?;
+                    text
                 }
-                RunStatus::Cancelling => {
-                    status.print("--- Run Cancelling");
+                Some(path) => std::fs::read_to_string(path)?,
+                None => prompt.join(" "),
+            };
+            let mut attachments = Vec::new();
+            if prompt.trim().is_empty() {
+                return Err("no prompt given".into());
+            }
+            for file in files {
+                let attachment = match file == Path::new("-") {
+                    true => Attachment::read_from(attachment::STDIN_NAME, stdin().lock())?,
+                    false => Attachment::read(file)?,
+                };
+                attachments.push(attachment);
+            }
+            attachment::check_total(&attachments)?;
+            let names: Vec<String> = attachments.iter().map(|a| a.name.clone()).collect();
+
+            let mut session = new_session(config, settings)?;
+            session.state.attachments = attachments;
+            let result = match session.start(config.resume.as_deref()).await {
+                Ok(()) if config.json => session.exchange(prompt.trim(), &mut sink()).await,
+                Ok(()) => session.exchange(prompt.trim(), &mut out).await,
+                Err(e) => Err(e),
+            };
+            let closed = session.close().await;
+            let message = result?;
+            closed?;
+            if config.json {
+                let mut value = export::message_json(&message);
+                value["attachments"] = json!(names);
+                writeln!(out, "{}", value)?;
+            }
+        }
+        Commands::Batch { script, output } => {
+            let text = match script == Path::new("-") {
+                true => {
+                    let mut text = String::new();
+                    stdin().read_to_string(&mut text)?;
+                    text
                 }
-                RunStatus::Cancelled => {
-                    status.print("--- Run Cancelled");
+                false => std::fs::read_to_string(script)?,
+            };
+            let turns = batch::parse(&text)
+                .map_err(|e| MorphaError::Batch(format!("{}: {}", script.display(), e)))?;
+            let mut file = match output {
+                Some(path) => Some(std::fs::File::create(path)?),
+                None => None,
+            };
+            let (target, json): (&mut dyn Write, bool) = match &mut file {
+                Some(file) => (file, true),
+                None => (&mut out, config.json),
+            };
+
+            let mut session = new_session(config, settings)?;
+            let result = match session.start(config.resume.as_deref()).await {
+                Ok(()) => batch::run(&mut session, &turns, target, json).await,
+                Err(e) => Err(e),
+            };
+            let closed = session.close().await;
+            let summary = result?;
+            closed?;
+            if summary.failed > 0 {
+                let reason = format!("{} of {} turns failed", summary.failed, summary.turns);
+                return Err(reason.into());
+            }
+        }
+        Commands::Search { terms } => {
+            let results = database::search(&db()?, &database::search_query(terms))?;
+            if results.is_empty() {
+                let reason = format!("no results for: {}", terms.join(" "));
+                return Err(CommandError::NotFound(reason).into());
+            }
+            match config.json {
+                true => {
+                    let results: Vec<Value> = results
+                        .iter()
+                        .map(|r| {
+                            let mut value = export::message_json(&r.message);
+                            value["rank"] = json!(r.rank);
+                            value
+                        })
+                        .collect();
+                    writeln!(out, "{}", serde_json::to_string_pretty(&results)?)?;
                 }
-                RunStatus::Expired => {
-                    status.print("--- Run Expired");
+                false => commands::write_search_results(&mut out, &results)?,
+            }
+        }
+        Commands::Models => {
+            let client = backend::client(settings.api_base.as_deref(), &settings.policy);
+            let models = backend::list_models(&client).await?;
+            match config.json {
+                true => writeln!(out, "{}", json!(models))?,
+                false => {
+                    for id in models {
+                        writeln!(out, "{}", id)?;
+                    }
                 }
-                RunStatus::RequiresAction => {
-                    status.print("--- Run Requires Action");
+            }
+        }
+        Commands::List { listing } => match listing {
+            Listing::Conversations { page } => {
+                let offset = (page - 1) * commands::PAGE_SIZE;
+                let conversations =
+                    conversation::list_conversations(&db()?, commands::PAGE_SIZE, offset)?;
+                match config.json {
+                    true => {
+                        let conversations: Vec<Value> = conversations
+                            .iter()
+                            .map(|c| {
+                                json!({
+                                    "id": c.id,
+                                    "timestamp": export::timestamp(c.msec),
+                                    "messages": c.message_count,
+                                    "first_prompt": c.first_prompt,
+                                })
+                            })
+                            .collect();
+                        writeln!(out, "{}", serde_json::to_string_pretty(&conversations)?)?;
+                    }
+                    false => commands::write_conversations(&mut out, &conversations)?,
                 }
-                RunStatus::InProgress => {
-                    if status_previous.is_none() {
-                        status.print("--- Waiting for response...");
-                    } else if let Some(RunStatus::InProgress) = status_previous {
-                        status.print(".");
+            }
+            Listing::Messages { conversation, page } => {
+                let db = db()?;
+                let id = commands::resolve_conversation_id(&db, conversation)?;
+                let offset = (page - 1) * commands::PAGE_SIZE;
+                let messages = conversation::list_messages(&db, &id, commands::PAGE_SIZE, offset)?;
+                match config.json {
+                    true => {
+                        let messages: Vec<Value> =
+                            messages.iter().map(export::message_json).collect();
+                        writeln!(out, "{}", serde_json::to_string_pretty(&messages)?)?;
                     }
+                    false => commands::write_messages(&mut out, &messages)?,
                 }
             }
-            status_previous = Some(run.status);
-            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        },
+        Commands::Show { conversation } => {
+            let db = db()?;
+            let id = commands::resolve_conversation_id(&db, conversation)?;
+            let Some(conversation) = conversation::read_conversation(&db, &id)? else {
+                return Err(
+                    CommandError::NotFound(format!("no conversation found: {}", id)).into(),
+                );
+            };
+            let format = match config.json {
+                true => export::Format::Json,
+                false => export::Format::Markdown,
+            };
+            write!(out, "{}", export::export(&conversation, format))?;
+        }
+        Commands::Config { action } => match action {
+            ConfigAction::Show => write!(out, "{}", settings.to_toml())?,
+        },
+        Commands::Persona { action } => match action {
+            PersonaAction::Render { file } => {
+                let personality = read_personality(file)?;
+                let dir = file.parent().unwrap_or(Path::new("."));
+                let env = Environment::current();
+                let instructions = template::render(&personality.instructions, &env, dir)?;
+                writeln!(out, "{}", instructions.trim_end())?;
+            }
+        },
+        Commands::Export {
+            conversation,
+            all,
+            format,
+            output,
+        } => {
+            let db = db()?;
+            let ids = match conversation {
+                Some(id) => vec![commands::resolve_conversation_id(&db, id)?],
+                None if *all => conversation::list_conversations(&db, i64::MAX as usize, 0)?
+                    .into_iter()
+                    .map(|c| c.id)
+                    .collect(),
+                None => Vec::new(),
+            };
+            let to_stdout = output == Path::new("-");
+            if !to_stdout {
+                std::fs::create_dir_all(output)?;
+            }
+            for id in ids {
+                let Some(conversation) = conversation::read_conversation(&db, &id)? else {
+                    continue;
+                };
+                if to_stdout {
+                    write!(out, "{}", export::export(&conversation, *format))?;
+                    continue;
+                }
+                let path = output.join(format!("{}.{}", id, format.extension()));
+                std::fs::write(&path, export::export(&conversation, *format))?;
+                writeln!(out, "{}", path.display())?;
+            }
+        }
+        Commands::Import { files } => {
+            // read every file before archiving any so a bad file imports nothing
+            let mut conversations = Vec::new();
+            for file in files {
+                conversations.extend(import::read(file)?);
+            }
+            let summary = import::archive(&db()?, &conversations)?;
+            writeln!(
+                out,
+                "imported {} conversations and {} messages, added to {} already archived, \
+                 skipped {} unchanged",
+                summary.conversations, summary.messages, summary.updated, summary.skipped
+            )?;
         }
-        first_run = false;
     }
-
-    // remove assistant and threads
-    client.assistants().delete(&assistant_id).await?;
-    client.threads().delete(&thread.id).await?;
-
     Ok(())
 }
 
-/// Parse command into Vector of strings before execution
-fn parse_command(command: &str) -> Result<Vec<String>, Box<dyn Error>> {
-    println!("command: {}", command);
-    // split command from args
-    let args: Vec<String> = command.split_whitespace().map(|x| x.to_string()).collect();
-    Ok(args)
-}
-
-// Run morpha commands
-fn run_command(command: &str) -> Result<(), Box<dyn Error>> {
-    let cmd = parse_command(command)?;
-    println!("command parsed: {:?}", cmd);
-    Ok(())
+/// Read a personality file, named Morpha unless its front matter says otherwise
+fn read_personality(path: &Path) -> Result<Personality, MorphaError> {
+    let text = std::fs::read_to_string(path)?;
+    Personality::parse("Morpha", &text)
+        .map_err(|e| MorphaError::Config(format!("{}:{}", path.display(), e)))
 }
@@ -0,0 +1,965 @@
+use crate::highlight::{self, Highlighter, Theme};
+
+use std::io::Write;
+
+/// Delimiter beginning and ending a fenced code block
+pub const FENCE: &str = "```";
+
+/// Most characters held back waiting for a possible link to close
+const LINK_MAX_CHARS: usize = 500;
+
+/// Select Graphic Rendition parameters of each style
+const SGR_BOLD: &str = "1";
+const SGR_ITALIC: &str = "3";
+const SGR_CODE: &str = "36";
+const SGR_LINK: &str = "4;34";
+const SGR_HEADING: &str = "1;35";
+const SGR_DIM: &str = "2";
+const SGR_RESET: &str = "\x1b[0m";
+
+/// How responses are printed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// Exactly as received, without wrapping
+    Raw,
+    /// Markdown rendered as plain text, for output that is piped
+    Plain,
+    /// Markdown rendered with ANSI styling, for a terminal
+    Styled,
+}
+
+/// A fenced code block of a response
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodeBlock {
+    /// Tag following the opening fence, as `rust` in ```rust
+    pub language: Option<String>,
+    /// Lines of code, each ending with a newline
+    pub code: String,
+}
+
+/// The fenced code blocks of `text` in order, including one left unclosed at the end
+pub fn code_blocks(text: &str) -> Vec<CodeBlock> {
+    let mut blocks = Vec::new();
+    let mut block: Option<CodeBlock> = None;
+    for line in text.lines() {
+        let Some(tag) = line.trim_start().strip_prefix(FENCE) else {
+            if let Some(block) = block.as_mut() {
+                block.code.push_str(line);
+                block.code.push('\n');
+            }
+            continue;
+        };
+        match block.take() {
+            Some(closed) => blocks.push(closed),
+            None => {
+                let tag = tag.trim_start_matches('`').trim();
+                block = Some(CodeBlock {
+                    language: (!tag.is_empty()).then(|| tag.to_string()),
+                    code: String::new(),
+                })
+            }
+        }
+    }
+    blocks.extend(block);
+    blocks
+}
+
+/// How the remainder of the line being rendered is treated
+#[derive(Debug, PartialEq)]
+enum Line {
+    /// Too little received to tell what the line is
+    Start,
+    /// Words wrapped at the width, as in paragraphs, headings, list items and quotes
+    Text,
+    /// Code, or a fence delimiter, printed as received
+    Code,
+    /// Row of a table, held until the whole table has arrived
+    Row,
+}
+
+/// Markup beginning a line
+enum Lead {
+    Fence,
+    Code,
+    Row,
+    /// Text following `len` bytes of markup, printed after `first` and wrapped onto lines
+    /// beginning with `rest`
+    Text {
+        len: usize,
+        first: String,
+        rest: String,
+        heading: bool,
+    },
+}
+
+/// Inline styles in effect
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    code: bool,
+    link: bool,
+}
+
+/// Alignment of a table column, given by its delimiter cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Align {
+    Left,
+    Right,
+    Center,
+}
+
+impl Align {
+    fn of(delimiter: &str) -> Self {
+        match (delimiter.starts_with(':'), delimiter.ends_with(':')) {
+            (true, true) => Align::Center,
+            (false, true) => Align::Right,
+            _ => Align::Left,
+        }
+    }
+}
+
+/// Text between spaces, rendered and ready to print
+struct Piece {
+    text: String,
+    /// Characters visible in the terminal
+    width: usize,
+    /// Style in effect at the start of the piece
+    style: Style,
+}
+
+impl Piece {
+    fn new(style: Style) -> Self {
+        Self {
+            text: String::new(),
+            width: 0,
+            style,
+        }
+    }
+
+    fn push(&mut self, c: char) {
+        self.text.push(c);
+        self.width += 1;
+    }
+}
+
+/// Incremental renderer printing Markdown for the terminal as a response is received
+///
+/// Prose is wrapped at word boundaries, list items and quotes with hanging indents, while fenced
+/// code blocks are printed untouched. Markup is removed, and replaced with ANSI styling when the
+/// format is `Format::Styled`, which also highlights code blocks tagged with a known language.
+pub struct Renderer<W: Write> {
+    out: W,
+    width: Option<usize>,
+    format: Format,
+    line: Line,
+    /// Beginning of the line held back until its kind is known
+    held: String,
+    in_block: bool,
+    /// Whether the current line is the fence opening a code block
+    opening: bool,
+    theme: Theme,
+    /// Highlighter of the code block being received, if its language is known
+    highlighter: Option<Highlighter>,
+    /// Line of code held until it is complete, or the tag of an opening fence
+    code: String,
+    /// Rows of the table being received
+    rows: Vec<String>,
+    /// Word not yet complete
+    word: String,
+    /// Complete words held back while a link may be open
+    pending: String,
+    style: Style,
+    heading: bool,
+    /// Printed at the start of lines the current line is wrapped onto
+    indent: String,
+    /// Whether the previous line was text an indented line continues, as in a list item
+    continued: bool,
+    /// Width of the line's leading markup, before which it is never broken
+    margin: usize,
+    /// Characters printed on the current line
+    column: usize,
+}
+
+impl<W: Write> Renderer<W> {
+    /// Render to `out`, wrapping prose at `width` characters if given
+    pub fn new(out: W, width: Option<usize>, format: Format) -> Self {
+        Self {
+            out,
+            width,
+            format,
+            line: Line::Start,
+            held: String::new(),
+            in_block: false,
+            opening: false,
+            theme: Theme::Dark,
+            highlighter: None,
+            code: String::new(),
+            rows: Vec::new(),
+            word: String::new(),
+            pending: String::new(),
+            style: Style::default(),
+            heading: false,
+            indent: String::new(),
+            continued: false,
+            margin: 0,
+            column: 0,
+        }
+    }
+
+    /// Highlight code blocks with `theme` when styled
+    pub fn with_theme(mut self, theme: Theme) -> Self {
+        self.theme = theme;
+        self
+    }
+
+    /// Render a fragment of the response, printing all that can be decided so far
+    pub fn write(&mut self, text: &str) -> std::io::Result<()> {
+        if self.format == Format::Raw {
+            self.emit(text)?;
+        } else {
+            for c in text.chars() {
+                self.push(c)?;
+            }
+        }
+        self.out.flush()
+    }
+
+    /// Print whatever remains held back and end the final line, returning the writer
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.line == Line::Start && !self.held.is_empty() {
+            self.begin(true)?;
+        }
+        match self.line {
+            Line::Text => self.end_text()?,
+            Line::Row => self.line = Line::Start,
+            Line::Code => self.end_code()?,
+            _ => {}
+        }
+        self.end_table()?;
+        if self.column > 0 {
+            self.emit("\n")?;
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+
+    fn push(&mut self, c: char) -> std::io::Result<()> {
+        match self.line {
+            Line::Start => {
+                if c == '\n' {
+                    self.begin(true)?;
+                    return self.push(c);
+                }
+                self.held.push(c);
+                self.begin(false)?;
+            }
+            Line::Code if self.highlighter.is_some() => match c {
+                '\n' => {
+                    self.end_code()?;
+                    self.emit("\n")?;
+                    self.line = Line::Start;
+                }
+                c => self.code.push(c),
+            },
+            Line::Code => {
+                self.emit(c.encode_utf8(&mut [0; 4]))?;
+                if c == '\n' {
+                    if self.opening {
+                        self.opening = false;
+                        self.start_code();
+                    }
+                    self.line = Line::Start;
+                } else if self.opening {
+                    self.code.push(c);
+                }
+            }
+            Line::Row => match c {
+                '\n' => self.line = Line::Start,
+                c => self.rows.last_mut().unwrap().push(c),
+            },
+            Line::Text => {
+                if c == '\n' {
+                    self.end_text()?;
+                    self.emit("\n")?;
+                    self.line = Line::Start;
+                } else if c.is_whitespace() {
+                    self.end_word()?;
+                } else {
+                    self.word.push(c);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Decide the kind of line from what was held back, if it can be decided, and replay it
+    fn begin(&mut self, complete: bool) -> std::io::Result<()> {
+        let Some(lead) = self.classify(complete) else {
+            return Ok(());
+        };
+        if !matches!(lead, Lead::Row) {
+            self.end_table()?;
+        }
+        // inline styles continue onto the next line of a paragraph
+        let blank = self.held.trim().is_empty();
+        if blank || !matches!(lead, Lead::Text { .. }) {
+            self.style = Style::default();
+        }
+        self.continued = !blank && matches!(lead, Lead::Text { .. });
+        let held = std::mem::take(&mut self.held);
+        match lead {
+            Lead::Fence => {
+                self.in_block = !self.in_block;
+                self.opening = self.in_block;
+                self.highlighter = None;
+                self.line = Line::Code;
+                self.code = held.clone();
+                self.emit(&held)?;
+            }
+            Lead::Code => {
+                self.line = Line::Code;
+                match self.highlighter {
+                    Some(_) => self.code = held,
+                    None => self.emit(&held)?,
+                }
+            }
+            Lead::Row => {
+                self.line = Line::Row;
+                self.rows.push(held);
+            }
+            Lead::Text {
+                len,
+                first,
+                rest,
+                heading,
+            } => {
+                self.line = Line::Text;
+                self.heading = heading;
+                self.indent = rest;
+                self.emit(&first)?;
+                self.margin = self.column;
+                if self.format == Format::Styled && (heading || self.style != Style::default()) {
+                    let codes = self.codes(self.style);
+                    self.emit(&codes)?;
+                }
+                for c in held[len..].chars() {
+                    self.push(c)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The markup beginning the held line, or `None` if more must be received to tell
+    fn classify(&self, complete: bool) -> Option<Lead> {
+        let t = self.held.trim_start_matches([' ', '\t']);
+        let lead = self.held.len() - t.len();
+        if t.starts_with(FENCE) {
+            return Some(Lead::Fence);
+        }
+        if !complete && FENCE.starts_with(t) {
+            return None;
+        }
+        if self.in_block {
+            return Some(Lead::Code);
+        }
+        let styled = self.format == Format::Styled;
+        let text = |len: usize, first: String, rest: String| {
+            Some(Lead::Text {
+                len,
+                first,
+                rest,
+                heading: false,
+            })
+        };
+        // a marker must be followed by a space, so wait to see what follows one
+        let marker = |n: usize| -> Option<bool> {
+            match t[n..].chars().next() {
+                Some(c) => Some(c == ' '),
+                None if complete => Some(false),
+                None => None,
+            }
+        };
+        match t.chars().next() {
+            None => text(self.held.len(), String::new(), String::new()),
+            Some('|') => Some(Lead::Row),
+            Some('#') => {
+                let n = t.chars().take_while(|c| *c == '#').count();
+                let spaced = marker(n)?;
+                match n <= 6 && (spaced || t.len() == n) {
+                    true => Some(Lead::Text {
+                        len: lead + n + usize::from(spaced),
+                        first: String::new(),
+                        rest: String::new(),
+                        heading: true,
+                    }),
+                    false => text(lead, String::new(), String::new()),
+                }
+            }
+            Some('>') => {
+                let n = t.chars().take_while(|c| *c == '>').count();
+                let spaced = marker(n)?;
+                let prefix = match styled {
+                    true => format!("\x1b[{}m{}{} ", SGR_DIM, "│".repeat(n), SGR_RESET),
+                    false => format!("{} ", ">".repeat(n)),
+                };
+                text(lead + n + usize::from(spaced), prefix.clone(), prefix)
+            }
+            Some(c @ ('-' | '*' | '+')) => match marker(1)? {
+                true => {
+                    let bullet = match styled {
+                        true => '•',
+                        false => c,
+                    };
+                    let first = format!("{}{} ", " ".repeat(lead), bullet);
+                    text(lead + 2, first, " ".repeat(lead + 2))
+                }
+                false => text(lead, String::new(), String::new()),
+            },
+            Some('0'..='9') => {
+                let n = t.chars().take_while(char::is_ascii_digit).count();
+                if !t[n..].starts_with(['.', ')']) {
+                    if !complete && t.len() == n {
+                        return None;
+                    }
+                    return text(lead, String::new(), String::new());
+                }
+                match marker(n + 1)? {
+                    true => {
+                        let first = format!("{}{} ", " ".repeat(lead), &t[..n + 1]);
+                        text(lead + n + 2, first, " ".repeat(lead + n + 2))
+                    }
+                    false => text(lead, String::new(), String::new()),
+                }
+            }
+            // an indented line continues a list item or quote with its indent
+            Some(_) if lead > 0 && self.continued => {
+                text(lead, self.indent.clone(), self.indent.clone())
+            }
+            Some(_) => text(lead, String::new(), String::new()),
+        }
+    }
+
+    /// Add the completed word to those waiting to be printed
+    fn end_word(&mut self) -> std::io::Result<()> {
+        if self.word.is_empty() {
+            return Ok(());
+        }
+        if !self.pending.is_empty() {
+            self.pending.push(' ');
+        }
+        self.pending.push_str(&std::mem::take(&mut self.word));
+        if !link_open(&self.pending) || self.pending.len() > LINK_MAX_CHARS {
+            self.print_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Print the rest of a line of text, ending any styles
+    fn end_text(&mut self) -> std::io::Result<()> {
+        self.end_word()?;
+        self.print_pending()?;
+        if self.format == Format::Styled && (self.heading || self.style != Style::default()) {
+            self.emit(SGR_RESET)?;
+        }
+        self.heading = false;
+        Ok(())
+    }
+
+    fn print_pending(&mut self) -> std::io::Result<()> {
+        let text = std::mem::take(&mut self.pending);
+        for piece in self.inline(&text) {
+            self.place(piece)?;
+        }
+        Ok(())
+    }
+
+    /// Print a piece of text after a space, first breaking the line if it would exceed the width
+    fn place(&mut self, piece: Piece) -> std::io::Result<()> {
+        if piece.width == 0 {
+            // nothing but styling
+            return self.emit(&piece.text);
+        }
+        let mut space = self.column > self.margin;
+        if let Some(width) = self.width {
+            if space && self.column + 1 + piece.width > width {
+                let styled = self.format == Format::Styled
+                    && (self.heading || piece.style != Style::default());
+                if styled {
+                    self.emit(SGR_RESET)?;
+                }
+                let indent = self.indent.clone();
+                self.emit("\n")?;
+                self.emit(&indent)?;
+                self.margin = self.column;
+                if styled {
+                    let codes = self.codes(piece.style);
+                    self.emit(&codes)?;
+                }
+                space = false;
+            }
+        }
+        if space {
+            self.emit(" ")?;
+        }
+        self.emit(&piece.text)
+    }
+
+    /// Render inline markup, returning the pieces of text between spaces
+    fn inline(&mut self, text: &str) -> Vec<Piece> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut pieces = Vec::new();
+        let mut piece = Piece::new(self.style);
+        // the link being rendered: the end of its label, its destination and its label
+        let mut link: Option<(usize, String, String)> = None;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if link.as_ref().is_some_and(|(end, _, _)| *end == i) {
+                let (end, url, label) = link.take().unwrap();
+                self.set_style(&mut piece, |s| s.link = false);
+                // a bare address is printed once
+                if label != url {
+                    pieces.push(std::mem::replace(&mut piece, Piece::new(self.style)));
+                    self.push_dim(&mut piece, &format!("({})", url));
+                }
+                // skip the destination, "](url)"
+                i = end + url.chars().count() + 3;
+                continue;
+            }
+            match c {
+                ' ' => {
+                    pieces.push(std::mem::replace(&mut piece, Piece::new(self.style)));
+                }
+                '\\' if chars.get(i + 1).is_some_and(char::is_ascii_punctuation) => {
+                    piece.push(chars[i + 1]);
+                    i += 1;
+                }
+                '`' => {
+                    let run = chars[i..].iter().take_while(|c| **c == '`').count();
+                    self.set_style(&mut piece, |s| s.code = !s.code);
+                    i += run;
+                    continue;
+                }
+                '*' | '_' if !self.style.code => {
+                    let run = chars[i..].iter().take_while(|d| **d == c).count();
+                    let before = i.checked_sub(1).map(|j| chars[j]);
+                    let after = chars.get(i + run).copied();
+                    let outside = |c: Option<char>| c.is_none_or(|c| !c.is_alphanumeric());
+                    // underscores within words, as in snake_case, are not emphasis
+                    let flanking = c == '*' || outside(before) || outside(after);
+                    let isolated = before.is_none_or(char::is_whitespace)
+                        && after.is_none_or(char::is_whitespace);
+                    if run > 3 || !flanking || isolated {
+                        for _ in 0..run {
+                            piece.push(c);
+                        }
+                    } else {
+                        self.set_style(&mut piece, |s| {
+                            if run != 2 {
+                                s.italic = !s.italic;
+                            }
+                            if run >= 2 {
+                                s.bold = !s.bold;
+                            }
+                        });
+                    }
+                    i += run;
+                    continue;
+                }
+                '[' if !self.style.code && link.is_none() => match find_link(&chars, i) {
+                    Some((end, url)) => {
+                        link = Some((end, url, chars[i + 1..end].iter().collect()));
+                        self.set_style(&mut piece, |s| s.link = true);
+                    }
+                    None => piece.push(c),
+                },
+                c => piece.push(c),
+            }
+            i += 1;
+        }
+        pieces.push(piece);
+        pieces
+    }
+
+    /// Change the style, marking the change in `piece` when styling
+    fn set_style(&mut self, piece: &mut Piece, change: impl FnOnce(&mut Style)) {
+        change(&mut self.style);
+        if self.format == Format::Styled {
+            piece.text.push_str(&self.codes(self.style));
+        }
+    }
+
+    /// Add `text` to `piece` dimmed, as for the destination of a link
+    fn push_dim(&self, piece: &mut Piece, text: &str) {
+        let styled = self.format == Format::Styled;
+        if styled {
+            piece.text.push_str(&format!("\x1b[{}m", SGR_DIM));
+        }
+        for c in text.chars() {
+            piece.push(c);
+        }
+        if styled {
+            piece.text.push_str(&self.codes(self.style));
+        }
+    }
+
+    /// Escape sequence selecting `style` within the current line
+    fn codes(&self, style: Style) -> String {
+        let mut parameters = vec!["0"];
+        if self.heading {
+            parameters.push(SGR_HEADING);
+        }
+        if style.bold {
+            parameters.push(SGR_BOLD);
+        }
+        if style.italic {
+            parameters.push(SGR_ITALIC);
+        }
+        if style.code {
+            parameters.push(SGR_CODE);
+        }
+        if style.link {
+            parameters.push(SGR_LINK);
+        }
+        format!("\x1b[{}m", parameters.join(";"))
+    }
+
+    /// Print the table received so far with its columns aligned
+    fn end_table(&mut self) -> std::io::Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+        let styled = self.format == Format::Styled;
+        let mut header = false;
+        let mut aligns = Vec::new();
+        let mut table: Vec<Vec<Piece>> = Vec::new();
+        for row in std::mem::take(&mut self.rows) {
+            let cells = split_row(&row);
+            if cells.iter().all(|c| is_delimiter(c)) {
+                // the delimiter row follows the header
+                header = table.len() == 1;
+                aligns = cells.iter().map(|c| Align::of(c)).collect();
+                continue;
+            }
+            let rendered = cells
+                .iter()
+                .map(|cell| {
+                    self.style = Style::default();
+                    let mut joined = Piece::new(Style::default());
+                    for (i, piece) in self.inline(cell).into_iter().enumerate() {
+                        if i > 0 {
+                            joined.push(' ');
+                        }
+                        joined.text.push_str(&piece.text);
+                        joined.width += piece.width;
+                    }
+                    if styled && self.style != Style::default() {
+                        joined.text.push_str(SGR_RESET);
+                    }
+                    joined
+                })
+                .collect();
+            table.push(rendered);
+        }
+        self.style = Style::default();
+
+        let columns = table.iter().map(Vec::len).max().unwrap_or(0);
+        let widths: Vec<usize> = (0..columns)
+            .map(|i| {
+                let cells = table.iter().filter_map(|row| row.get(i));
+                cells.map(|c| c.width).max().unwrap_or(0)
+            })
+            .collect();
+        let (separator, rule, cross) = match styled {
+            true => (format!(" \x1b[{}m│{} ", SGR_DIM, SGR_RESET), "─", "─┼─"),
+            false => (" | ".to_string(), "-", "-+-"),
+        };
+        for (r, row) in table.iter().enumerate() {
+            let bold = styled && header && r == 0;
+            let mut line = String::new();
+            for (i, width) in widths.iter().enumerate() {
+                if i > 0 {
+                    line.push_str(&separator);
+                }
+                let (text, used) = row.get(i).map_or(("", 0), |c| (c.text.as_str(), c.width));
+                let padding = width - used;
+                let before = match aligns.get(i) {
+                    Some(Align::Right) => padding,
+                    Some(Align::Center) => padding / 2,
+                    _ => 0,
+                };
+                line.push_str(&" ".repeat(before));
+                if bold {
+                    line.push_str(&format!("\x1b[{}m{}{}", SGR_BOLD, text, SGR_RESET));
+                } else {
+                    line.push_str(text);
+                }
+                // no trailing spaces after the last column
+                if i + 1 < widths.len() {
+                    line.push_str(&" ".repeat(padding - before));
+                }
+            }
+            self.emit(&line)?;
+            self.emit("\n")?;
+            if header && r == 0 {
+                let rules: Vec<String> = widths.iter().map(|w| rule.repeat(*w)).collect();
+                let line = match styled {
+                    true => format!("\x1b[{}m{}{}", SGR_DIM, rules.join(cross), SGR_RESET),
+                    false => rules.join(cross),
+                };
+                self.emit(&line)?;
+                self.emit("\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Choose a highlighter for the code block opened by the fence held in `code`
+    fn start_code(&mut self) {
+        let fence = std::mem::take(&mut self.code);
+        let tag = fence.trim().trim_start_matches('`');
+        if self.format == Format::Styled && self.theme != Theme::Off {
+            let theme = self.theme;
+            self.highlighter = highlight::language(tag).map(|l| Highlighter::new(l, theme));
+        }
+    }
+
+    /// Print the line of code held for highlighting
+    fn end_code(&mut self) -> std::io::Result<()> {
+        let code = std::mem::take(&mut self.code);
+        if let Some(highlighter) = self.highlighter.as_mut() {
+            let line = highlighter.line(&code);
+            self.emit(&line)?;
+        }
+        Ok(())
+    }
+
+    fn emit(&mut self, text: &str) -> std::io::Result<()> {
+        match text.rfind('\n') {
+            Some(i) => self.column = visible_width(&text[i + 1..]),
+            None => self.column += visible_width(text),
+        }
+        self.out.write_all(text.as_bytes())
+    }
+}
+
+/// Whether `text` contains the start of a link whose destination has not yet been received
+fn link_open(text: &str) -> bool {
+    let Some(start) = text.rfind('[') else {
+        return false;
+    };
+    let rest = &text[start..];
+    match rest.find("](") {
+        Some(i) => !rest[i..].contains(')'),
+        None => !rest.contains(']'),
+    }
+}
+
+/// End of the label and the destination of a link starting at `start`, as in `[label](url)`
+fn find_link(chars: &[char], start: usize) -> Option<(usize, String)> {
+    let end = start + chars[start..].iter().position(|c| *c == ']')?;
+    if chars[start + 1..end].contains(&'[') || chars.get(end + 1) != Some(&'(') {
+        return None;
+    }
+    let close = end + 2 + chars[end + 2..].iter().position(|c| *c == ')')?;
+    let url: String = chars[end + 2..close].iter().collect();
+    match url.is_empty() || url.contains(char::is_whitespace) {
+        true => None,
+        false => Some((end, url)),
+    }
+}
+
+/// Cells of a table row, without the outer pipes
+fn split_row(row: &str) -> Vec<String> {
+    let row = row.trim();
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row.strip_suffix('|').unwrap_or(row);
+    row.split('|').map(|c| c.trim().to_string()).collect()
+}
+
+/// Whether a cell belongs to the row separating the header from the body, as in `:---:`
+fn is_delimiter(cell: &str) -> bool {
+    cell.contains('-') && cell.chars().all(|c| matches!(c, '-' | ':' | ' '))
+}
+
+/// Characters of `text` visible in a terminal, not counting escape sequences
+pub fn visible_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip to the final byte of the sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            width += 1;
+        }
+    }
+    width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render `text` one character at a time, as the least favourable stream would deliver it
+    fn render(text: &str, width: Option<usize>, format: Format) -> String {
+        let mut renderer = Renderer::new(Vec::new(), width, format);
+        for c in text.chars() {
+            renderer.write(c.encode_utf8(&mut [0; 4])).unwrap();
+        }
+        String::from_utf8(renderer.finish().unwrap()).unwrap()
+    }
+
+    fn plain(text: &str) -> String {
+        render(text, Some(30), Format::Plain)
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!(
+            plain("Some **bold**, *italic*, ***both*** and `a * b`"),
+            "Some bold, italic, both and a\n* b\n"
+        );
+        assert_eq!(
+            plain("keep snake_case, 2 * 3 and \\*stars\\*"),
+            "keep snake_case, 2 * 3 and\n*stars*\n"
+        );
+        assert_eq!(
+            plain("Read [the docs](https://a.io/x) or <https://a.io>"),
+            "Read the docs (https://a.io/x)\nor <https://a.io>\n"
+        );
+        assert_eq!(
+            plain("[https://a.io](https://a.io) [1]"),
+            "https://a.io [1]\n"
+        );
+    }
+
+    #[test]
+    fn test_headings() {
+        assert_eq!(
+            plain("# Title\n### Part *two*\n#tag"),
+            "Title\nPart two\n#tag\n"
+        );
+        assert_eq!(
+            render("## Title", None, Format::Styled),
+            "\x1b[0;1;35mTitle\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn test_lists() {
+        let text = "- a bullet long enough to wrap onto another line\n  - nested\n\
+                    1. first numbered item that wraps too\n   lazily continued\n-not a list";
+        assert_eq!(
+            plain(text),
+            "- a bullet long enough to wrap\n  onto another line\n  - nested\n\
+             1. first numbered item that\n   wraps too\n   lazily continued\n-not a list\n"
+        );
+        assert!(render("* item", None, Format::Styled).starts_with("• item"));
+    }
+
+    #[test]
+    fn test_quotes() {
+        assert_eq!(
+            plain("> a quotation long enough to wrap\n>> nested"),
+            "> a quotation long enough to\n> wrap\n>> nested\n"
+        );
+    }
+
+    #[test]
+    fn test_tables() {
+        let text =
+            "| Name | Count | Note |\n|---|--:|:-:|\n| `a` | 1 | x |\n| longer | 22 | yz |\n\nDone";
+        assert_eq!(
+            plain(text),
+            "Name   | Count | Note\n-------+-------+-----\na      |     1 |  x\nlonger |    22 |  yz\n\nDone\n"
+        );
+        // a table ending the response is printed when it finishes
+        assert_eq!(plain("|a|b|\n|c|d|"), "a | b\nc | d\n");
+    }
+
+    #[test]
+    fn test_code_untouched() {
+        let text = "```md\n# not a heading\n- **raw**\n```\n**bold**";
+        assert_eq!(
+            plain(text),
+            "```md\n# not a heading\n- **raw**\n```\nbold\n"
+        );
+    }
+
+    #[test]
+    fn test_styled() {
+        assert_eq!(
+            render("a **b c** `d`", Some(3), Format::Styled),
+            "a \x1b[0;1mb\x1b[0m\n\x1b[0;1mc\x1b[0m \x1b[0;36md\x1b[0m\n"
+        );
+        // styles carry onto the next line of a paragraph
+        assert_eq!(
+            render("*a\nb*", None, Format::Styled),
+            "\x1b[0;3ma\x1b[0m\n\x1b[0;3mb\x1b[0m\n"
+        );
+        let output = render("[x](https://a.io)", None, Format::Styled);
+        assert_eq!(
+            output,
+            "\x1b[0;4;34mx\x1b[0m \x1b[2m(https://a.io)\x1b[0m\n"
+        );
+        assert_eq!(visible_width(&output), 17);
+    }
+
+    #[test]
+    fn test_highlighted() {
+        let text = "```rust\nlet x = 1;\n```\n```nonsense\nlet x = 1;\n```";
+        assert_eq!(
+            render(text, None, Format::Styled),
+            "```rust\n\x1b[35mlet\x1b[0m x = \x1b[36m1\x1b[0m;\n```\n```nonsense\nlet x = 1;\n```\n"
+        );
+        // never when plain or switched off
+        assert_eq!(
+            plain(text),
+            "```rust\nlet x = 1;\n```\n```nonsense\nlet x = 1;\n```\n"
+        );
+        let mut renderer = Renderer::new(Vec::new(), None, Format::Styled).with_theme(Theme::Off);
+        renderer.write(text).unwrap();
+        assert_eq!(
+            String::from_utf8(renderer.finish().unwrap()).unwrap(),
+            plain(text)
+        );
+    }
+
+    #[test]
+    fn test_code_blocks() {
+        let text = "Try:\n```rust\nfn main() {}\n\n```\nor\n  ```\necho hi\n```\n```py\nprint(1)";
+        assert_eq!(
+            code_blocks(text),
+            vec![
+                CodeBlock {
+                    language: Some("rust".to_string()),
+                    code: "fn main() {}\n\n".to_string(),
+                },
+                CodeBlock {
+                    language: None,
+                    code: "echo hi\n".to_string(),
+                },
+                CodeBlock {
+                    language: Some("py".to_string()),
+                    code: "print(1)\n".to_string(),
+                },
+            ]
+        );
+        assert!(code_blocks("no code").is_empty());
+    }
+
+    #[test]
+    fn test_raw() {
+        assert_eq!(
+            render("# keep  **it**", Some(5), Format::Raw),
+            "# keep  **it**\n"
+        );
+    }
+}
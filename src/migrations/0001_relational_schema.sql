@@ -0,0 +1,60 @@
+CREATE TABLE conversations(
+    id TEXT PRIMARY KEY,
+    msec INTEGER NOT NULL
+);
+
+CREATE INDEX conversations_msec ON conversations(msec);
+
+CREATE TABLE messages(
+    id INTEGER PRIMARY KEY,
+    conversation_id TEXT NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    msec INTEGER NOT NULL,
+    prompt TEXT NOT NULL,
+    response TEXT NOT NULL
+);
+
+CREATE INDEX messages_conversation_id ON messages(conversation_id, msec);
+CREATE INDEX messages_msec ON messages(msec);
+
+-- archived messages included as context when a new message was sent
+CREATE TABLE citations(
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    cited_message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    PRIMARY KEY (message_id, cited_message_id)
+);
+
+CREATE INDEX citations_cited_message_id ON citations(cited_message_id);
+
+-- messages sent to explain an archived message or conversation in more detail
+CREATE TABLE explanations(
+    message_id INTEGER PRIMARY KEY REFERENCES messages(id) ON DELETE CASCADE,
+    source_message_id INTEGER REFERENCES messages(id) ON DELETE SET NULL,
+    source_conversation_id TEXT REFERENCES conversations(id) ON DELETE SET NULL,
+    focus TEXT
+);
+
+-- full text search index over messages, kept current by the triggers below
+CREATE VIRTUAL TABLE messages_search USING fts5(
+    prompt,
+    response,
+    conversation_id UNINDEXED,
+    content='messages',
+    content_rowid='id'
+);
+
+CREATE TRIGGER messages_search_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_search(rowid, prompt, response, conversation_id)
+    VALUES (new.id, new.prompt, new.response, new.conversation_id);
+END;
+
+CREATE TRIGGER messages_search_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_search(messages_search, rowid, prompt, response, conversation_id)
+    VALUES ('delete', old.id, old.prompt, old.response, old.conversation_id);
+END;
+
+CREATE TRIGGER messages_search_update AFTER UPDATE ON messages BEGIN
+    INSERT INTO messages_search(messages_search, rowid, prompt, response, conversation_id)
+    VALUES ('delete', old.id, old.prompt, old.response, old.conversation_id);
+    INSERT INTO messages_search(rowid, prompt, response, conversation_id)
+    VALUES (new.id, new.prompt, new.response, new.conversation_id);
+END;
@@ -0,0 +1,9 @@
+-- details of the request producing each response, unknown for older messages
+ALTER TABLE messages ADD COLUMN model TEXT;
+ALTER TABLE messages ADD COLUMN assistant TEXT;
+ALTER TABLE messages ADD COLUMN prompt_tokens INTEGER;
+ALTER TABLE messages ADD COLUMN completion_tokens INTEGER;
+ALTER TABLE messages ADD COLUMN started_msec INTEGER;
+ALTER TABLE messages ADD COLUMN finished_msec INTEGER;
+ALTER TABLE messages ADD COLUMN run_id TEXT;
+ALTER TABLE messages ADD COLUMN status TEXT;
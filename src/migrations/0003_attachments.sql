@@ -0,0 +1,8 @@
+-- documents attached to the prompt of a message, such as files or piped input
+CREATE TABLE attachments(
+    message_id INTEGER NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    bytes INTEGER NOT NULL
+);
+
+CREATE INDEX attachments_message_id ON attachments(message_id);
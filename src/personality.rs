@@ -1,11 +1,37 @@
-const MAX_CHARS_DEFAULT: usize = 80;
+use crate::error::MorphaError;
+use crate::highlight::Theme;
+use crate::markdown::{Format, Renderer};
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Characters per line of wrapped prose unless configured otherwise
+pub const MAX_CHARS_DEFAULT: usize = 80;
+
+/// Delimiter beginning and ending the front matter of a personality file
+const FRONT_MATTER: &str = "---";
+
+/// Extension of the personality files in a personalities directory
+const EXTENSION: &str = "md";
+
+/// Highest sampling temperature accepted by the API
+const TEMPERATURE_MAX: f32 = 2.0;
 
 /// A personality that we can customize
+#[derive(Debug)]
 pub struct Personality {
     pub mode: Mode,
     pub name: String,
     pub instructions: String, // read from markdown
     pub max_chars: Option<usize>,
+    /// How responses are printed
+    pub format: Format,
+    /// Colors highlighting code blocks when styled
+    pub theme: Theme,
+    /// Model answering as this personality instead of the configured one
+    pub model: Option<String>,
+    /// Sampling temperature, or the API's default if `None`
+    pub temperature: Option<f32>,
 }
 
 impl Personality {
@@ -16,111 +42,157 @@ impl Personality {
             mode: Mode::NonInteractive,
             instructions: instructions.to_string(),
             max_chars: Some(MAX_CHARS_DEFAULT),
+            format: Format::Plain,
+            theme: Theme::Dark,
+            model: None,
+            temperature: None,
         }
     }
 
-    /// Extract code blocks to avoid wrapping
-    pub fn speak(&self, text: &str) {
-        let lines: Vec<&str> = text.lines().collect();
-        let mut output: Vec<String> = Vec::new();
-        let mut code: Vec<String> = Vec::new();
-        let mut speech: Vec<String> = Vec::new();
-
-        let mut in_block = false;
-        let mut block: Vec<&str> = Vec::new();
-        for line in lines.iter() {
-            // determine if this line is a code delimiter
-            let delimiter_found = line.starts_with("```");
-
-            match in_block {
-                false => {
-                    if delimiter_found {
-                        // we are ENTERING a code block
-                        in_block = true;
-                        block.push(line); // delimiter ``` is always pushed to code
-                        output.push(line.to_string());
-                    } else {
-                        speech.push(line.to_string());
-                        output.push(
-                            // wrap lines based on personality config
-                            match self.max_chars {
-                                Some(_) => self.split_at_word(line),
-                                None => line.to_string(),
-                            },
-                        );
-                    }
-                }
-                true => {
-                    block.push(line);
-                    output.push(line.to_string());
-                    if delimiter_found {
-                        // we are EXITING a code block
-                        in_block = false;
+    /// Create a personality from a markdown file's contents, which may begin with front matter
+    /// giving its `name`, `model` and `temperature`, named `name` unless the front matter says
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let mut personality = Self::new(name, text);
+        let mut lines = text.split_inclusive('\n');
+        if lines.next().map(str::trim_end) != Some(FRONT_MATTER) {
+            return Ok(personality);
+        }
+        let mut number = 1;
+        for line in lines.by_ref() {
+            number += 1;
+            let line = line.trim();
+            if line == FRONT_MATTER {
+                personality.instructions = lines.collect::<String>().trim_start().to_string();
+                return Ok(personality);
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(':')
+                .ok_or_else(|| format!("{}: expected <key>: <value>", number))?;
+            let value = unquote(value.trim());
+            match key.trim() {
+                "name" => personality.name = value.to_string(),
+                "model" => personality.model = Some(value.to_string()),
+                "temperature" => {
+                    personality.temperature = match value.parse() {
+                        Ok(t) if (0.0..=TEMPERATURE_MAX).contains(&t) => Some(t),
+                        _ => {
+                            return Err(format!(
+                                "{}: temperature must be a number from 0 to {}",
+                                number, TEMPERATURE_MAX
+                            ))
+                        }
                     }
                 }
-            }
-
-            // push when delimiter is found or on last iteration for final speech
-            if delimiter_found && !block.is_empty() {
-                code.push(block.join("\n"));
-                block.clear();
+                key => return Err(format!("{}: unknown front matter: {}", number, key)),
             }
         }
+        Err("front matter does not end".to_string())
+    }
 
-        /*
-        println!(">>>SPEECH");
-        println!("{:?}", speech);
-        println!(">>>END SPEECH");
-
-        println!(">>>CODE");
-        println!("{:?}", code);
-        println!(">>>END CODE");
+    /// Read a personality file, named after the file unless its front matter says otherwise
+    pub fn read(path: &Path) -> Result<Self, MorphaError> {
+        let text = std::fs::read_to_string(path)?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        Self::parse(&name, &text)
+            .map_err(|e| MorphaError::Config(format!("{}:{}", path.display(), e)))
+    }
 
-        println!(">>>OUTPUT");
-        println!("{}", output.join("\n"));
-        println!(">>>END OUTPUT\n");
-         */
+    /// Print text to `out`, rendering its Markdown
+    pub fn speak<W: Write>(&self, out: W, text: &str) -> std::io::Result<()> {
+        let mut stream = self.stream(out);
+        stream.write(text)?;
+        stream.finish()?;
+        Ok(())
+    }
 
-        println!("{}", output.join("\n"));
+    /// Begin incrementally rendering a response to `out` as it is received
+    pub fn stream<W: Write>(&self, out: W) -> Renderer<W> {
+        Renderer::new(out, self.max_chars, self.format).with_theme(self.theme)
     }
 
     /// Short message without wrapping
     pub fn speak_raw(&self, message: &str) {
         println!("{}: {}", self.name, message);
     }
-
-    /// Split `message` into lines at whitespace honoring `self.max_chars`
-    fn split_at_word(&self, message: &str) -> String {
-        let max_chars: usize = match self.max_chars {
-            Some(v) => v,
-            None => return message.to_string(),
-        };
-
-        // split the message at whitespace and fold into readable lines
-        let mut lines = Vec::new();
-        let mut line = String::new();
-        for word in message.split_whitespace() {
-            if line.chars().count() + word.chars().count() + 1 > max_chars {
-                lines.push(line);
-                line = String::new();
-            }
-            line.push_str(word);
-            line.push(' ');
-        }
-        if line.chars().count() > 0 {
-            lines.push(line);
-        }
-        lines.join("\n")
-    }
 }
 
 /// Mode of interaction for the assistant
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum Mode {
     Interactive,
     NonInteractive,
 }
 
+/// Personality files in the personalities directory `dir`, sorted by path
+fn files(dir: &Path) -> Result<Vec<PathBuf>, MorphaError> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_file() && path.extension().is_some_and(|e| e == EXTENSION) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Every personality in the personalities directory `dir` sorted by name, and the errors of
+/// files that could not be read
+pub fn read_dir(dir: &Path) -> Result<(Vec<Personality>, Vec<MorphaError>), MorphaError> {
+    let (mut personalities, mut errors) = (Vec::new(), Vec::new());
+    for path in files(dir)? {
+        match Personality::read(&path) {
+            Ok(personality) => personalities.push(personality),
+            Err(e) => errors.push(e),
+        }
+    }
+    personalities.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((personalities, errors))
+}
+
+/// The personality in `dir` called `name`, ignoring case
+///
+/// A file named for the personality is read first, so a malformed file for another personality
+/// does not get in the way, and otherwise the names in the front matter of the files are matched.
+pub fn find(dir: &Path, name: &str) -> Result<Personality, MorphaError> {
+    let files = files(dir)?;
+    let stem = |path: &PathBuf| {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    if let Some(path) = files.iter().find(|p| stem(p).eq_ignore_ascii_case(name)) {
+        return Personality::read(path);
+    }
+    files
+        .iter()
+        .filter_map(|path| Personality::read(path).ok())
+        .find(|p| p.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| MorphaError::Config(format!("no personality named: {}", name)))
+}
+
+/// A front matter value without any quotes around it
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(v) = value
+            .strip_prefix(quote)
+            .and_then(|v| v.strip_suffix(quote))
+        {
+            return v;
+        }
+    }
+    value
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -144,13 +216,117 @@ Now, let me explain each line of code:
 "#;
 
         let p = Personality::new("name", "You are an assistant");
-        p.speak(msg);
+        p.speak(std::io::stdout(), msg).unwrap();
+    }
+
+    /// Render `chunks` as though they arrived one at a time from a stream
+    fn render(personality: &Personality, chunks: &[&str]) -> String {
+        let mut stream = personality.stream(Vec::new());
+        for chunk in chunks {
+            stream.write(chunk).unwrap();
+        }
+        String::from_utf8(stream.finish().unwrap()).unwrap()
     }
 
     #[test]
-    fn test_personality_split_at_word() {
+    fn test_personality_stream_wraps_prose() {
         let personality = Personality::new("Morpha", "");
-        let output = personality.split_at_word(include_str!("../data/lorem_ipsum.txt"));
-        assert_eq!(6, output.matches('\n').count()); // count newlines
+        let output = render(&personality, &[include_str!("../data/lorem_ipsum.txt")]);
+        assert_eq!(7, output.matches('\n').count()); // count newlines
+        assert!(output
+            .lines()
+            .all(|line| line.chars().count() <= MAX_CHARS_DEFAULT));
+    }
+
+    #[test]
+    fn test_personality_stream_code_block() {
+        let mut personality = Personality::new("Morpha", "");
+        personality.max_chars = Some(10);
+        let text = "Some code follows\n```rust\nlet   x = \"a long line of code\";\n```\nDone";
+        assert_eq!(
+            render(&personality, &[text]),
+            "Some code\nfollows\n```rust\nlet   x = \"a long line of code\";\n```\nDone\n"
+        );
+    }
+
+    #[test]
+    fn test_personality_stream_chunks() {
+        let text =
+            "A paragraph long enough to be wrapped at least once by the renderer when printed.\n\n\
+                    ```\nfn main() {}\n```\n`inline` ``code`` and more prose";
+        let personality = Personality::new("Morpha", "");
+        let whole = render(&personality, &[text]);
+        // the output must not depend on where the stream splits the text
+        let chars: Vec<String> = text.chars().map(String::from).collect();
+        let chars: Vec<&str> = chars.iter().map(String::as_str).collect();
+        assert_eq!(render(&personality, &chars), whole);
+        assert_eq!(render(&personality, &[&text[..40], &text[40..]]), whole);
+        assert!(whole.contains("\n```\nfn main() {}\n```\n"));
+    }
+
+    #[test]
+    fn test_personality_stream_raw() {
+        let mut personality = Personality::new("Morpha", "");
+        personality.max_chars = None;
+        personality.format = Format::Raw;
+        assert_eq!(
+            render(&personality, &["keep   **spacing**", "\nas is"]),
+            "keep   **spacing**\nas is\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_front_matter() {
+        let text = "---\nname: Reviewer\nmodel: \"gpt-4o\"\ntemperature: 0.2\n---\n\nReview code tersely.\n";
+        let p = Personality::parse("reviewer", text).unwrap();
+        assert_eq!(p.name, "Reviewer");
+        assert_eq!(p.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(p.temperature, Some(0.2));
+        assert_eq!(p.instructions, "Review code tersely.\n");
+
+        // without front matter the whole text is instructions
+        let p = Personality::parse("tutor", "Teach patiently.\n---\n").unwrap();
+        assert_eq!(p.name, "tutor");
+        assert_eq!(p.model, None);
+        assert_eq!(p.instructions, "Teach patiently.\n---\n");
+
+        let error = |text| Personality::parse("x", text).unwrap_err();
+        assert_eq!(
+            error("---\ncolour: red\n---\n"),
+            "2: unknown front matter: colour"
+        );
+        assert_eq!(
+            error("---\ntemperature: 3\n---\n"),
+            "2: temperature must be a number from 0 to 2"
+        );
+        assert_eq!(error("---\nname: x\n"), "front matter does not end");
+    }
+
+    #[test]
+    fn test_read_dir() {
+        let dir = std::env::temp_dir().join(format!("morpha-personalities-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tutor.md"), "Teach patiently.").unwrap();
+        std::fs::write(dir.join("review.md"), "---\nname: Reviewer\n---\nBe terse.").unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a personality").unwrap();
+
+        std::fs::write(dir.join("broken.md"), "---\ncolour: red\n---\n").unwrap();
+
+        // a malformed file is reported without hiding the others
+        let (personalities, errors) = read_dir(&dir).unwrap();
+        let names: Vec<String> = personalities.into_iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["Reviewer", "tutor"]);
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0]
+            .to_string()
+            .contains("broken.md:2: unknown front matter"));
+
+        assert_eq!(find(&dir, "reviewer").unwrap().instructions, "Be terse.");
+        assert_eq!(find(&dir, "Review").unwrap().name, "Reviewer");
+        assert_eq!(find(&dir, "TUTOR").unwrap().name, "tutor");
+        assert!(find(&dir, "broken").is_err());
+        assert!(find(&dir, "translator").is_err());
+        assert!(read_dir(&dir.join("missing")).unwrap().0.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
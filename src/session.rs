@@ -0,0 +1,367 @@
+use crate::attachment;
+use crate::backend::Backend;
+use crate::citation;
+use crate::commands::{self, Action, CommandError, Registry, State};
+use crate::conversation::{self, Conversation, Message};
+use crate::database;
+use crate::error::MorphaError;
+use crate::interrupt::Interrupt;
+use crate::markdown;
+use crate::personality::Mode::{Interactive, NonInteractive};
+use crate::personality::{self, Personality};
+use crate::status::Status;
+use crate::template::{self, Environment};
+
+use rusqlite::Connection;
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+/// A conversation between the user and a backend, archived as it progresses
+pub struct Session {
+    pub personality: Personality,
+    pub status: Status,
+    pub db: Connection,
+    pub backend: Box<dyn Backend>,
+    pub registry: Registry,
+    pub state: State,
+    pub conversation: Conversation,
+    /// Archive prompts and responses in the database
+    pub archive: bool,
+    /// Ends the session when triggered
+    pub interrupt: Interrupt,
+    /// Directory of the personalities `/persona` switches between
+    pub personalities: Option<PathBuf>,
+    /// Print only the code blocks of responses
+    pub extract_code: bool,
+    /// Whether the conversation has been written to the database
+    archived: bool,
+}
+
+impl Session {
+    /// Create a session for a new conversation
+    pub fn new(personality: Personality, db: Connection, backend: Box<dyn Backend>) -> Self {
+        Self {
+            personality,
+            status: Status::new(),
+            db,
+            backend,
+            registry: Registry::default(),
+            state: State::default(),
+            conversation: Conversation {
+                id: uuid::Uuid::new_v4().to_string(),
+                messages: Vec::new(),
+                msec: database::current_msec(),
+            },
+            archive: true,
+            interrupt: Interrupt::new(),
+            personalities: None,
+            extract_code: false,
+            archived: false,
+        }
+    }
+
+    /// Start the backend, continuing the archived conversation `resume` if given
+    pub async fn start(&mut self, resume: Option<&str>) -> Result<(), MorphaError> {
+        let resumed = match resume {
+            Some(id) => Some(self.read_conversation(id)?),
+            None => None,
+        };
+        let history = resumed.as_ref().map_or(&[][..], |c| &c.messages[..]);
+        self.backend.start(&self.personality, history).await?;
+        if let Some(conversation) = resumed {
+            self.resumed(conversation);
+        }
+        Ok(())
+    }
+
+    /// Read prompts from `input` until the user quits or input ends, printing responses to `out`
+    ///
+    /// Problems with a single exchange are reported and the session continues, as it does when
+    /// the interrupt cancels a response. The session ends with `MorphaError::Interrupted` if the
+    /// interrupt is triggered while waiting for input.
+    pub async fn run(
+        &mut self,
+        input: &mut (dyn AsyncBufRead + Unpin),
+        out: &mut dyn Write,
+    ) -> Result<(), MorphaError> {
+        let interrupt = self.interrupt.clone();
+        if let Interactive = self.personality.mode {
+            // Initial greeting
+            self.personality.speak(&mut *out, "How may I assist you?")?;
+            self.status.print("\n");
+        }
+
+        let mut empty_commands = 0;
+        loop {
+            // show data prompt read user input
+            self.status.print("> ");
+            let mut line = String::new();
+
+            // in the case of non-interactive session, read all lines from standard input
+            let mode = &self.personality.mode;
+            let read = tokio::select! {
+                biased;
+                _ = interrupt.wait() => {
+                    self.status.print("\n");
+                    return Err(MorphaError::Interrupted);
+                }
+                read = async {
+                    match mode {
+                        Interactive => input.read_line(&mut line).await,
+                        NonInteractive => input.read_to_string(&mut line).await,
+                    }
+                } => read?,
+            };
+            if read == 0 {
+                break; // end of input
+            }
+
+            // ignore empty line and print exit instructions
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                empty_commands += 1;
+                // be nice
+                if empty_commands >= 2 {
+                    self.status
+                        .print("/q, /quit, or /exit to leave application, /help for commands\n");
+                }
+                continue;
+            }
+            empty_commands = 0; // reset
+            self.status.print("\n"); // I like readability
+
+            // process custom commands
+            if line.starts_with(commands::PREFIX) {
+                let mut ctx = commands::Context {
+                    registry: &self.registry,
+                    db: &self.db,
+                    out: &mut *out,
+                    state: &mut self.state,
+                };
+                match self.registry.run(&mut ctx, &line) {
+                    Ok(Action::Quit) => break,
+                    Ok(Action::Continue) => continue,
+                    Ok(Action::Prompt(prompt)) => line = prompt,
+                    Ok(Action::Resume(id)) => {
+                        if let Err(e) = self.resume(&id).await {
+                            self.status.error(&e);
+                        }
+                        continue;
+                    }
+                    Ok(Action::Persona(name)) => {
+                        if let Err(e) = self.persona(name.as_deref(), &mut *out).await {
+                            self.status.error(&e);
+                        }
+                        continue;
+                    }
+                    Err(e) => {
+                        self.status.error(&e);
+                        continue;
+                    }
+                }
+            }
+
+            if let Err(e) = self.exchange(&line, &mut *out).await {
+                // a script piping a prompt needs to know it failed
+                if let NonInteractive = self.personality.mode {
+                    return Err(e);
+                }
+                self.status.error(&e);
+                continue;
+            }
+
+            // exit if one response is requested
+            if let NonInteractive = self.personality.mode {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `input` with any attachments and citations, print the response to `out` as it streams in and archive it
+    ///
+    /// Returns the message added to the conversation.
+    pub async fn exchange(
+        &mut self,
+        input: &str,
+        out: &mut dyn Write,
+    ) -> Result<Message, MorphaError> {
+        let result = self.send(input, out).await;
+        // cited and attached material goes with this prompt only, whether or not it was answered
+        self.state.citations.clear();
+        self.state.attachments.clear();
+        self.state.explanation = None;
+        let message = result?;
+        self.conversation.messages.push(message.clone());
+        Ok(message)
+    }
+
+    /// Send the prompt of an exchange and archive the response
+    async fn send(&mut self, input: &str, out: &mut dyn Write) -> Result<Message, MorphaError> {
+        let started_msec = database::current_msec();
+        let prompt = citation::cite(input, &self.state.citations);
+        let prompt = attachment::attach(&prompt, &self.state.attachments);
+
+        // print the response as it streams in, clearing the status line on the first text
+        self.status.print("--- Waiting for response...");
+        let mut sink = std::io::sink();
+        let target: &mut dyn Write = match self.extract_code {
+            true => &mut sink,
+            false => &mut *out,
+        };
+        let mut stream = self.personality.stream(target);
+        let mut waiting = true;
+        let status = &mut self.status;
+        let interrupt = self.interrupt.clone();
+        let mut on_text = |text: &str| {
+            if waiting {
+                status.clear_line();
+                waiting = false;
+            }
+            stream.write(text)
+        };
+        let result = tokio::select! {
+            result = self.backend.send(&prompt, &mut on_text) => result,
+            _ = interrupt.wait() => Err(MorphaError::Cancelled),
+        };
+        if waiting {
+            self.status.clear_line();
+        }
+        stream.finish()?;
+        if let Err(MorphaError::Cancelled) = result {
+            // stop the remote run so the conversation can continue
+            self.backend.cancel().await?;
+        }
+        let reply = result?;
+        let finished_msec = database::current_msec();
+        self.state.code = markdown::code_blocks(&reply.text);
+        if self.extract_code {
+            for block in &self.state.code {
+                out.write_all(block.code.as_bytes())?;
+            }
+        }
+        self.status.print("\n"); // I really like readability
+
+        let mut message = Message {
+            id: None,
+            conversation_id: self.conversation.id.clone(),
+            msec: finished_msec,
+            prompt: input.to_string(),
+            response: reply.text,
+            model: Some(reply.model),
+            assistant: Some(self.personality.name.clone()),
+            prompt_tokens: reply.prompt_tokens,
+            completion_tokens: reply.completion_tokens,
+            started_msec: Some(started_msec),
+            finished_msec: Some(finished_msec),
+            run_id: reply.run_id,
+            status: Some(reply.status),
+        };
+        if self.archive {
+            message.id = Some(self.write_to_database(&message)?);
+        }
+        Ok(message)
+    }
+
+    /// Continue the archived conversation `id` with its full history
+    pub async fn resume(&mut self, id: &str) -> Result<(), MorphaError> {
+        let conversation = self.read_conversation(id)?;
+        self.backend.load_history(&conversation.messages).await?;
+        self.resumed(conversation);
+        Ok(())
+    }
+
+    /// Answer the prompts that follow as the personality `name` from the personalities directory,
+    /// or list the personalities available if no name is given
+    pub async fn persona(
+        &mut self,
+        name: Option<&str>,
+        out: &mut dyn Write,
+    ) -> Result<(), MorphaError> {
+        let dir = self.personalities.as_deref().ok_or_else(|| {
+            MorphaError::Command("no personalities directory is configured".into())
+        })?;
+        let Some(name) = name else {
+            let (personalities, errors) = personality::read_dir(dir)?;
+            for e in errors {
+                self.status.error(&format!("warning: {}", e));
+            }
+            if personalities.is_empty() {
+                writeln!(out, "no personalities in {}", dir.display())?;
+            }
+            for p in personalities {
+                let current = if p.name == self.personality.name {
+                    "*"
+                } else {
+                    " "
+                };
+                let model = p.model.map(|m| format!(" ({})", m)).unwrap_or_default();
+                writeln!(out, "{} {}{}", current, p.name, model)?;
+            }
+            return Ok(());
+        };
+
+        let mut personality = personality::find(dir, name)?;
+        personality.instructions =
+            template::render(&personality.instructions, &Environment::current(), dir)?;
+        personality.mode = self.personality.mode;
+        personality.max_chars = self.personality.max_chars;
+        personality.format = self.personality.format;
+        personality.theme = self.personality.theme;
+        self.backend.set_personality(&personality).await?;
+        self.status
+            .print(&format!("--- Switched to {}\n\n", personality.name));
+        self.personality = personality;
+        Ok(())
+    }
+
+    /// Release the backend's remote resources
+    pub async fn close(&mut self) -> Result<(), MorphaError> {
+        self.backend.close().await
+    }
+
+    fn resumed(&mut self, conversation: Conversation) {
+        self.status.print(&format!(
+            "--- Resumed conversation {} with {} messages\n\n",
+            conversation.id,
+            conversation.messages.len()
+        ));
+        if let Some(last) = conversation.messages.last() {
+            self.state.code = markdown::code_blocks(&last.response);
+        }
+        self.conversation = conversation;
+        self.archived = true;
+    }
+
+    /// Read an archived conversation by id or unambiguous prefix
+    fn read_conversation(&self, id: &str) -> Result<Conversation, MorphaError> {
+        let id = commands::resolve_conversation_id(&self.db, id)?;
+        match conversation::read_conversation(&self.db, &id)? {
+            Some(c) => Ok(c),
+            None => Err(MorphaError::Command(
+                CommandError::NotFound(format!("no conversation found: {}", id)).into(),
+            )),
+        }
+    }
+
+    /// Archive a message with its citations, attachments and explanation, returning its id
+    fn write_to_database(&mut self, message: &Message) -> Result<i64, MorphaError> {
+        // Write the conversation only after valid input and response has been obtained.
+        // Otherwise, we will have empty conversations when user input is cancelled.
+        // The rows of a message are written together or not at all.
+        let tx = self.db.unchecked_transaction()?;
+        if !self.archived {
+            self.conversation.write_to_database(&tx)?;
+        }
+        let message_id = message.write_to_database(&tx)?;
+        citation::write_to_database(&tx, message_id, &self.state.citations)?;
+        attachment::write_to_database(&tx, message_id, &self.state.attachments)?;
+        if let Some(explanation) = &self.state.explanation {
+            explanation.write_to_database(&tx, message_id)?;
+        }
+        tx.commit()?;
+        self.archived = true;
+        Ok(message_id)
+    }
+}
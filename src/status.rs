@@ -1,16 +1,30 @@
-#[derive(Default)]
+use std::fmt::Display;
+use std::io::{stderr, Write};
+
 pub struct Status {
     pub silent: bool,
+    out: Box<dyn Write>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Status {
     /// Creates a new `Status` struct for isolating system message output on stderr
     pub fn new() -> Self {
-        Self { silent: true }
+        Self::with_writer(Box::new(stderr()))
+    }
+
+    /// Creates a new `Status` struct printing system messages to `out`
+    pub fn with_writer(out: Box<dyn Write>) -> Self {
+        Self { silent: true, out }
     }
 
     /// Clear a partially printed line that has not printed a final newline character
-    pub fn clear_line(&self) {
+    pub fn clear_line(&mut self) {
         for _ in 0..100 {
             self.print("\u{8}");
             self.print("\r"); // just to be sure
@@ -18,10 +32,16 @@ impl Status {
     }
 
     /// Print text to standard error
-    pub fn print(&self, text: &str) {
+    pub fn print(&mut self, text: &str) {
         if self.silent {
             return;
         }
-        eprint!("{}", text);
+        let _ = self.out.write_all(text.as_bytes());
+        let _ = self.out.flush();
+    }
+
+    /// Report an error, even when silent
+    pub fn error(&mut self, e: &dyn Display) {
+        let _ = writeln!(self.out, "{}", e);
     }
 }
@@ -0,0 +1,165 @@
+use crate::error::MorphaError;
+
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// Delimiters surrounding a placeholder, as in `{{date}}`
+const OPEN: &str = "{{";
+const CLOSE: &str = "}}";
+
+/// Placeholder replaced with the contents of a file, as in `{{include notes.md}}`
+const INCLUDE: &str = "include";
+
+/// Values substituted for placeholders in personality instructions
+#[derive(Debug, Default)]
+pub struct Environment {
+    /// Current date, as 2024-01-31
+    pub date: String,
+    pub user: String,
+    pub cwd: String,
+    /// Branch checked out in the working directory, if it is in a git repository
+    pub git_branch: String,
+    pub os: String,
+}
+
+impl Environment {
+    /// Values describing the session being started
+    pub fn current() -> Self {
+        let cwd = std::env::current_dir().unwrap_or_default();
+        Self {
+            date: chrono::Local::now().format("%Y-%m-%d").to_string(),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .unwrap_or_default(),
+            git_branch: git_branch(&cwd).unwrap_or_default(),
+            cwd: cwd.to_string_lossy().to_string(),
+            os: std::env::consts::OS.to_string(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&str> {
+        match name {
+            "date" => Some(&self.date),
+            "user" => Some(&self.user),
+            "cwd" => Some(&self.cwd),
+            "git_branch" => Some(&self.git_branch),
+            "os" => Some(&self.os),
+            _ => None,
+        }
+    }
+}
+
+/// Expand the placeholders of `text`, reading included files relative to `dir`
+///
+/// Text between braces that is not a known placeholder is left as it is, so instructions
+/// quoting templates of their own are unaffected.
+pub fn render(text: &str, env: &Environment, dir: &Path) -> Result<String, MorphaError> {
+    let mut rendered = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(OPEN) {
+        let Some(end) = rest[start..].find(CLOSE).map(|end| start + end) else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + OPEN.len()..end];
+        match expand(placeholder.trim(), env, dir)? {
+            Some(value) => rendered.push_str(&value),
+            None => rendered.push_str(&rest[start..end + CLOSE.len()]),
+        }
+        rest = &rest[end + CLOSE.len()..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// The value of a placeholder, or `None` if it is not one
+fn expand(placeholder: &str, env: &Environment, dir: &Path) -> Result<Option<String>, MorphaError> {
+    if let Some(value) = env.get(placeholder) {
+        return Ok(Some(value.to_string()));
+    }
+    let Some(path) = placeholder
+        .strip_prefix(INCLUDE)
+        .filter(|p| p.starts_with(char::is_whitespace))
+    else {
+        return Ok(None);
+    };
+    let path = dir.join(unquote(path.trim()));
+    match std::fs::read_to_string(&path) {
+        Ok(text) => Ok(Some(text.trim_end().to_string())),
+        Err(e) => Err(MorphaError::Config(format!(
+            "cannot include {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Branch checked out in the git repository containing `dir`
+fn git_branch(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "--abbrev-ref", "HEAD"])
+        .current_dir(dir)
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A path without the quotes allowing it to contain spaces
+fn unquote(path: &str) -> &str {
+    path.strip_prefix('"')
+        .and_then(|p| p.strip_suffix('"'))
+        .unwrap_or(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env() -> Environment {
+        Environment {
+            date: "2024-01-31".to_string(),
+            user: "ryan".to_string(),
+            cwd: "/home/ryan/morpha".to_string(),
+            git_branch: "main".to_string(),
+            os: "linux".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let text = "Today is {{date}}. {{ user }} works in {{cwd}} on {{git_branch}} ({{os}}).";
+        assert_eq!(
+            render(text, &env(), Path::new(".")).unwrap(),
+            "Today is 2024-01-31. ryan works in /home/ryan/morpha on main (linux)."
+        );
+
+        // other braces are left alone
+        let text = "Use {{name}} in templates, and {{ unclosed";
+        assert_eq!(render(text, &env(), Path::new(".")).unwrap(), text);
+    }
+
+    #[test]
+    fn test_render_include() {
+        let dir = std::env::temp_dir().join(format!("morpha-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("style guide.md"), "Prefer short functions.\n").unwrap();
+
+        let text = "Follow these rules:\n{{include \"style guide.md\"}}\nThanks.";
+        assert_eq!(
+            render(text, &env(), &dir).unwrap(),
+            "Follow these rules:\nPrefer short functions.\nThanks."
+        );
+        let result = render("{{include missing.md}}", &env(), &dir);
+        assert!(matches!(result, Err(MorphaError::Config(_))));
+        // not an include without a path
+        assert_eq!(
+            render("{{included}}", &env(), &dir).unwrap(),
+            "{{included}}"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,292 @@
+use morpha::backend::mock::{Log, Mock};
+use morpha::personality::{Mode, Personality};
+use morpha::session::Session;
+use morpha::status::Status;
 use rusqlite::Connection;
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
 
 /// return a database connection
 pub fn setup() -> Result<Connection, rusqlite::Error> {
     Connection::open_in_memory()
 }
+
+/// Output shared with the session writing it, so it can be read afterwards
+#[derive(Clone, Default)]
+pub struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Buffer {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A session archiving to an in-memory database, with status messages written to the returned buffer
+pub fn session(mock: Mock, mode: Mode) -> (Session, Rc<RefCell<Log>>, Buffer) {
+    let log = mock.log();
+    let mut personality = Personality::new("Morpha", "You are a test.");
+    let interactive = matches!(mode, Mode::Interactive);
+    personality.mode = mode;
+    let db = morpha::database::open_in_memory().unwrap();
+    let mut session = Session::new(personality, db, Box::new(mock));
+    let status = Buffer::default();
+    session.status = Status::with_writer(Box::new(status.clone()));
+    session.status.silent = !interactive;
+    (session, log, status)
+}
+
+/// Run `session` to completion on `input`, returning what it printed to standard output
+pub fn run(session: &mut Session, input: &str) -> String {
+    let mut out = Vec::new();
+    block_on(async {
+        session.start(None).await.unwrap();
+        session.run(&mut input.as_bytes(), &mut out).await.unwrap();
+        session.close().await.unwrap();
+    });
+    String::from_utf8(out).unwrap()
+}
+
+/// Run a future to completion on a runtime like the application's
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Create an empty directory unique to this test process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("morpha-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A stand-in for an OpenAI-compatible server, answering every chat completion with one reply
+pub struct StandIn {
+    pub api_base: String,
+    requests: Arc<Mutex<Vec<String>>>,
+}
+
+/// How the stand-in answers chat completions
+#[derive(Clone, Copy, Default)]
+struct Behaviour {
+    /// Number of chat completions still to answer as though rate limited
+    failures: usize,
+    /// Whether to end the stream before the chunk finishing the choice
+    truncated: bool,
+    /// Whether to reject requests with stream options, as older local servers do
+    strict: bool,
+    /// Whether to leave chat completions unanswered, as when a model is slow to load
+    stalled: bool,
+}
+
+impl StandIn {
+    /// Serve on a local port until the test process exits
+    pub fn start(reply: &str) -> StandIn {
+        Self::rate_limited(reply, 0)
+    }
+
+    /// Serve, answering the first `failures` chat completions as though rate limited
+    pub fn rate_limited(reply: &str, failures: usize) -> StandIn {
+        Self::serve(
+            reply,
+            Behaviour {
+                failures,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Serve, ending every response stream after the text without finishing the choice
+    pub fn truncated(reply: &str) -> StandIn {
+        Self::serve(
+            reply,
+            Behaviour {
+                truncated: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Serve, rejecting chat completions that ask for stream options
+    pub fn strict(reply: &str) -> StandIn {
+        Self::serve(
+            reply,
+            Behaviour {
+                strict: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Serve, never answering chat completions
+    pub fn stalled() -> StandIn {
+        Self::serve(
+            "",
+            Behaviour {
+                stalled: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn serve(reply: &str, behaviour: Behaviour) -> StandIn {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let api_base = format!("http://{}/v1", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&requests);
+        let reply = reply.to_string();
+        let mut behaviour = behaviour;
+        std::thread::spawn(move || {
+            let mut unanswered = Vec::new();
+            for stream in listener.incoming().flatten() {
+                if let Some(request) = respond(&stream, &reply, &mut behaviour) {
+                    received.lock().unwrap().push(request);
+                }
+                if behaviour.stalled {
+                    // hold the connection open so the client keeps waiting
+                    unanswered.push(stream);
+                }
+            }
+        });
+        StandIn { api_base, requests }
+    }
+
+    /// Every request received, as the request line followed by the body
+    pub fn requests(&self) -> Vec<String> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Answer one request, returning its request line and body
+fn respond(stream: &TcpStream, reply: &str, behaviour: &mut Behaviour) -> Option<String> {
+    let mut reader = BufReader::new(stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).ok()?;
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header).ok()?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    let request_line = request_line.trim().to_string();
+    let request = format!("{}\n{}", request_line, String::from_utf8_lossy(&body));
+
+    let completion = request_line.starts_with("POST /v1/chat/completions");
+    if completion && behaviour.stalled {
+        return Some(request);
+    }
+    let (status, content_type, body_out) = if request_line.starts_with("GET /v1/models") {
+        ("200 OK", "application/json", models())
+    } else if completion
+        && behaviour.strict
+        && String::from_utf8_lossy(&body).contains("stream_options")
+    {
+        (
+            "400 Bad Request",
+            "application/json",
+            r#"{"error":{"message":"unknown field: stream_options"}}"#.to_string(),
+        )
+    } else if completion && behaviour.failures > 0 {
+        behaviour.failures -= 1;
+        (
+            "429 Too Many Requests",
+            "application/json",
+            r#"{"error":{"message":"rate limited","code":"rate_limit_exceeded"}}"#.to_string(),
+        )
+    } else if completion {
+        let events = completion_events(reply, !behaviour.truncated);
+        ("200 OK", "text/event-stream", events)
+    } else {
+        (
+            "404 Not Found",
+            "application/json",
+            r#"{"error":{"message":"not found"}}"#.to_string(),
+        )
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body_out.len(),
+        body_out
+    )
+    .ok()?;
+    Some(request)
+}
+
+fn models() -> String {
+    r#"{"object":"list","data":[
+        {"id":"llama3","object":"model","created":0,"owned_by":"library"},
+        {"id":"gemma","object":"model","created":0,"owned_by":"library"}]}"#
+        .to_string()
+}
+
+/// Server-sent events streaming `reply` a few characters at a time, then the finish reason and
+/// usage if `finished`, and the end of the stream
+fn completion_events(reply: &str, finished: bool) -> String {
+    let chunk = |choices: &str, usage: &str| {
+        format!(
+            "data: {{\"id\":\"chatcmpl-1\",\"object\":\"chat.completion.chunk\",\"created\":0,\
+             \"model\":\"llama3\",\"choices\":[{}],\"usage\":{}}}\n\n",
+            choices, usage
+        )
+    };
+    let mut events = String::new();
+    let chars: Vec<char> = reply.chars().collect();
+    for piece in chars.chunks(5) {
+        let piece: String = piece.iter().collect();
+        events.push_str(&chunk(
+            &format!(
+                "{{\"index\":0,\"delta\":{{\"content\":\"{}\"}},\"finish_reason\":null}}",
+                escape(&piece)
+            ),
+            "null",
+        ));
+    }
+    if finished {
+        events.push_str(&chunk(
+            "{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}",
+            "null",
+        ));
+        events.push_str(&chunk(
+            "",
+            "{\"prompt_tokens\":12,\"completion_tokens\":7,\"total_tokens\":19}",
+        ));
+    }
+    events.push_str("data: [DONE]\n\n");
+    events
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -1,6 +1,828 @@
 mod common;
 
+use morpha::backend::mock::Mock;
+use morpha::batch;
+use morpha::citation;
+use morpha::conversation;
+use morpha::error::MorphaError;
+use morpha::personality::Mode;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     common::setup()?;
     Ok(())
 }
+
+/// Run morpha against the stand-in server with `input` on standard input
+fn morpha(server: &common::StandIn, home: &std::path::Path, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_morpha"))
+        .args(["--api-base", &server.api_base])
+        .args(args)
+        .env("HOME", home)
+        .env("OPENAI_API_KEY", "test")
+        .env("NO_PROXY", "127.0.0.1")
+        .env_remove("OPENAI_API_BASE")
+        .env_remove("MORPHA_PROFILE")
+        .env_remove("XDG_CONFIG_HOME")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // morpha may exit without reading all of its input
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_config_show() {
+    let server = common::StandIn::start("");
+    let home = common::temp_dir("config");
+    let dir = home.join(".config/morpha");
+    std::fs::create_dir_all(&dir).unwrap();
+    let config =
+        "wrap = 100\n\n[profiles.local]\nmodel = \"llama3\"\ndatabase = \"~/local.sqlite3\"\n";
+    std::fs::write(dir.join("config.toml"), config).unwrap();
+
+    let args = [
+        "--profile-name",
+        "local",
+        "--timeout",
+        "30",
+        "config",
+        "show",
+    ];
+    let output = morpha(&server, &home, &args, "");
+    assert!(output.status.success());
+    let expected = format!(
+        "# profile: local\nmodel = \"llama3\"\nbackend = \"chat\"\n\
+         database = \"{home}/local.sqlite3\"\npersonality = \"{home}/.morpha_profile\"\n\
+         personalities = \"{home}/.config/morpha/personalities\"\nwrap = 100\ntheme = \"dark\"\napi_base = \"{}\"\ntimeout = 30\nretries = 3\n",
+        server.api_base,
+        home = home.display(),
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    let output = morpha(
+        &server,
+        &home,
+        &["--profile-name", "work", "config", "show"],
+        "",
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no profile named: work"));
+}
+
+#[test]
+fn test_persona_render() {
+    let server = common::StandIn::start("");
+    let home = common::temp_dir("render");
+    let personality = "---\nname: Reviewer\n---\nYou run on {{os}}.\n{{include rules.md}}\n";
+    std::fs::write(home.join("reviewer.md"), personality).unwrap();
+    std::fs::write(home.join("rules.md"), "Be terse.\n").unwrap();
+
+    let file = home.join("reviewer.md");
+    let output = morpha(
+        &server,
+        &home,
+        &["persona", "render", file.to_str().unwrap()],
+        "",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("You run on {}.\nBe terse.\n", std::env::consts::OS)
+    );
+}
+
+#[test]
+fn test_export() {
+    let server = common::StandIn::start("Yes, when using the ChatGPT API.");
+    let home = common::temp_dir("export");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    assert!(morpha(&server, &home, &[], "Send history?\n")
+        .status
+        .success());
+
+    let dir = home.join("exported");
+    let args = ["export", "--all", "--format", "text", "--output"];
+    let output = morpha(
+        &server,
+        &home,
+        &[&args[..], &[dir.to_str().unwrap()]].concat(),
+        "",
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let path = String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string();
+    assert!(path.ends_with(".txt"));
+    let text = std::fs::read_to_string(&path).unwrap();
+    assert!(text.starts_with("uuid: "));
+    assert!(text.contains("\nname: Yes when using the ChatGPT API\ntimestamp: "));
+    assert!(text.ends_with("\n----\nYes, when using the ChatGPT API.\n----\n"));
+
+    // without a conversation or --all there is nothing to export
+    assert!(!morpha(&server, &home, &["export"], "").status.success());
+}
+
+#[test]
+fn test_import() {
+    let server = common::StandIn::start("");
+    let home = common::temp_dir("import");
+    let files = [
+        concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/design/gpt_response_method.txt"
+        ),
+        concat!(env!("CARGO_MANIFEST_DIR"), "/design/name_suggestions.txt"),
+    ];
+    let args = [&["import"], &files[..]].concat();
+    let output = morpha(&server, &home, &args, "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "imported 2 conversations and 2 messages, added to 0 already archived, skipped 0 unchanged\n"
+    );
+    let output = morpha(&server, &home, &args, "");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "imported 0 conversations and 0 messages, added to 0 already archived, skipped 2 unchanged\n"
+    );
+
+    // imported messages can be searched
+    let db =
+        morpha::database::open_database(&home.join(".morpha.sqlite3").to_string_lossy()).unwrap();
+    let results = morpha::database::search(&db, "Chameleon").unwrap();
+    assert_eq!(
+        results[0].message.conversation_id,
+        "d3f8dd6a-95ba-4823-83ee-b4bce2f711a2"
+    );
+}
+
+#[test]
+fn test_scripting_subcommands() {
+    let server = common::StandIn::start("Water boils at 100 degrees.");
+    let home = common::temp_dir("scripting");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+
+    let output = morpha(
+        &server,
+        &home,
+        &["ask", "When", "does", "water", "boil?"],
+        "",
+    );
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Water boils at 100 degrees.\n"
+    );
+    let output = morpha(&server, &home, &["ask", "-"], "Piped question?\n");
+    assert!(output.status.success());
+    assert!(server.requests()[1].contains("Piped question?"));
+    std::fs::write(home.join("prompt.txt"), "And ice?\n").unwrap();
+    let file = home.join("prompt.txt");
+    let output = morpha(
+        &server,
+        &home,
+        &["ask", "--json", "--prompt-file", file.to_str().unwrap()],
+        "",
+    );
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(value["prompt"], "And ice?");
+    assert_eq!(value["response"], "Water boils at 100 degrees.");
+    assert_eq!(value["model"], "llama3");
+    assert!(server.requests()[2].contains("And ice?"));
+
+    let output = morpha(&server, &home, &["search", "--json", "ice"], "");
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(value.as_array().unwrap().len(), 1);
+    let id = value[0]["conversation_id"].as_str().unwrap().to_string();
+
+    let output = morpha(&server, &home, &["show", &id], "");
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        text.starts_with("# Water boils at 100 degrees\n"),
+        "{}",
+        text
+    );
+    assert!(text.contains("## Prompt\n\nAnd ice?\n"));
+
+    // scripts can tell what went wrong from the exit status
+    let output = morpha(&server, &home, &["search", "steam"], "");
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no results for: steam"));
+    let output = morpha(&server, &home, &["show", "missing"], "");
+    assert_eq!(output.status.code(), Some(3));
+    let output = morpha(&server, &home, &["ask"], "");
+    assert_eq!(output.status.code(), Some(1));
+    let output = morpha(&server, &home, &["ask", "--bogus"], "");
+    assert_eq!(output.status.code(), Some(2));
+    let output = morpha(
+        &server,
+        &home,
+        &["list", "conversations", "--page", "0"],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_ask_leaves_stdin_unread() {
+    let server = common::StandIn::start("Done.");
+    let home = common::temp_dir("stdin_open");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_morpha"))
+        .args(["--api-base", &server.api_base, "ask", "Hello?"])
+        .env("HOME", &home)
+        .env("OPENAI_API_KEY", "test")
+        .env("NO_PROXY", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // standard input is held open and never written, as one inherited from cron or make
+    let stdin = child.stdin.take();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break Some(status);
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            break None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    };
+    drop(stdin);
+    assert!(status.is_some_and(|s| s.success()));
+}
+
+#[test]
+fn test_ask_with_attachments() {
+    let server = common::StandIn::start("Looks good.");
+    let home = common::temp_dir("attachments");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    std::fs::write(home.join("notes.md"), "Keep functions short.\n").unwrap();
+    let notes = home.join("notes.md");
+
+    let args = [
+        "ask",
+        "--json",
+        "review this",
+        "--file",
+        "-",
+        "--file",
+        notes.to_str().unwrap(),
+    ];
+    let output = morpha(&server, &home, &args, "-old\n+new\n");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(value["prompt"], "review this");
+    assert_eq!(value["attachments"][0], "stdin");
+
+    // piped input and the file are sent between delimiters before the prompt
+    let request = &server.requests()[0];
+    assert!(request.contains(r#"<attachment name=\"stdin\">\n-old\n+new\n</attachment>"#));
+    assert!(request.contains(r"Keep functions short.\n</attachment>\n\nreview this"));
+
+    // the archive records what was attached
+    let db = rusqlite::Connection::open(home.join(".morpha.sqlite3")).unwrap();
+    let id = value["id"].as_i64().unwrap();
+    let attached = morpha::attachment::attached_to(&db, id).unwrap();
+    assert_eq!(attached[0], ("stdin".to_string(), 10));
+    assert_eq!(attached[1].1, 22);
+
+    std::fs::write(home.join("big.log"), "x".repeat(300 * 1024)).unwrap();
+    let big = home.join("big.log");
+    let output = morpha(
+        &server,
+        &home,
+        &["ask", "why?", "-f", big.to_str().unwrap()],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is more than the limit"));
+    let big = "x".repeat(300 * 1024);
+    let output = morpha(&server, &home, &["ask", "why?", "-f", "-"], &big);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("stdin: more than the limit"));
+    let output = morpha(&server, &home, &["ask", "-", "-f", "-"], "why?");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn test_batch() {
+    let server = common::StandIn::start("Noted.");
+    let home = common::temp_dir("batch");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    std::fs::write(
+        home.join("script.txt"),
+        "# warm up\nHello\n\nHow are you?\n",
+    )
+    .unwrap();
+    let script = home.join("script.txt");
+
+    let output = morpha(&server, &home, &["batch", script.to_str().unwrap()], "");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "> Hello\n\nNoted.\n\n> How are you?\n\nNoted.\n\n"
+    );
+    // the second prompt is sent with the first exchange as history
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[1].contains("Hello") && requests[1].contains("How are you?"));
+
+    let results = home.join("results.jsonl");
+    let args = ["batch", "-", "--output", results.to_str().unwrap()];
+    let output = morpha(
+        &server,
+        &home,
+        &args,
+        "{\"id\": \"greet\", \"prompt\": \"Hi\"}\n",
+    );
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty());
+    let text = std::fs::read_to_string(&results).unwrap();
+    let value: serde_json::Value = serde_json::from_str(text.trim()).unwrap();
+    assert_eq!(value["turn"], 1);
+    assert_eq!(value["turn_id"], "greet");
+    assert_eq!(value["response"], "Noted.");
+    assert_eq!(value["error"], serde_json::Value::Null);
+
+    let output = morpha(
+        &server,
+        &home,
+        &["batch", "-"],
+        "{\"prompt\": \"Hi\", \"x\": 1}\n",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 1: unknown option: x"));
+    assert_eq!(server.requests().len(), 3);
+}
+
+#[test]
+fn test_models_from_local_server() {
+    let server = common::StandIn::start("");
+    let home = common::temp_dir("models");
+    let output = morpha(&server, &home, &["models"], "");
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "gemma\nllama3\n");
+}
+
+#[test]
+fn test_prompt_to_local_server() {
+    let server = common::StandIn::start("Hello from a \"local\" model.");
+    let home = common::temp_dir("prompt");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let output = morpha(&server, &home, &["--model", "llama3"], "Say hello\n");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "Hello from a \"local\" model.\n"
+    );
+
+    // the prompt is sent after the instructions
+    let requests = server.requests();
+    assert_eq!(requests.len(), 1);
+    assert!(requests[0].starts_with("POST /v1/chat/completions"));
+    assert!(requests[0].contains("\"model\":\"llama3\""));
+    assert!(requests[0].contains("You are a test."));
+    assert!(requests[0].contains("Say hello"));
+
+    // the complete response is archived
+    let db = rusqlite::Connection::open(home.join(".morpha.sqlite3")).unwrap();
+    let row: (String, String, String, i64, i64, String) = db
+        .query_row(
+            "SELECT prompt, response, model, prompt_tokens, completion_tokens, status
+            FROM messages",
+            [],
+            |row| {
+                Ok((
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                ))
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        row,
+        (
+            "Say hello".to_string(),
+            "Hello from a \"local\" model.".to_string(),
+            "llama3".to_string(),
+            12,
+            7,
+            "stop".to_string()
+        )
+    );
+}
+
+#[test]
+fn test_retry_rate_limited() {
+    let server = common::StandIn::rate_limited("Finally.", 1);
+    let home = common::temp_dir("retry");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let output = morpha(&server, &home, &[], "Hello?");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Finally.\n");
+    assert_eq!(server.requests().len(), 2);
+
+    // without retries the rate limit is reported
+    let server = common::StandIn::rate_limited("Finally.", 1);
+    let output = morpha(&server, &home, &["--retries", "0"], "Hello?");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("429"));
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(server.requests().len(), 1);
+}
+
+#[test]
+fn test_stream_ended_early() {
+    let server = common::StandIn::truncated("Half of an answer");
+    let home = common::temp_dir("stream_ended");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let output = morpha(&server, &home, &["ask", "Hello?"], "");
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("response stream ended"), "{}", stderr);
+
+    // the partial response is not archived as though it were complete
+    let db = rusqlite::Connection::open(home.join(".morpha.sqlite3")).unwrap();
+    let count: i64 = db
+        .query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn test_server_without_stream_options() {
+    let server = common::StandIn::strict("Hello from an old server.");
+    let home = common::temp_dir("stream_options");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let output = morpha(&server, &home, &["ask", "--json", "Hi"], "");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let value: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(value["response"], "Hello from an old server.");
+
+    // the request is sent again without asking for usage
+    let requests = server.requests();
+    assert_eq!(requests.len(), 2);
+    assert!(requests[0].contains("stream_options"));
+    assert!(!requests[1].contains("stream_options"));
+}
+
+#[test]
+fn test_session_interactive() {
+    let mock = Mock::new().reply("Hello there.").reply("Goodbye.");
+    let (mut session, log, status) = common::session(mock, Mode::Interactive);
+    let stdout = common::run(&mut session, "Hi\n\nBye\n/quit\nnever sent\n");
+    assert_eq!(stdout, "How may I assist you?\nHello there.\nGoodbye.\n");
+    assert!(status.text().contains("> "));
+    assert!(status.text().contains("--- Waiting for response..."));
+
+    let log = log.borrow();
+    assert_eq!(log.instructions.as_deref(), Some("You are a test."));
+    assert_eq!(log.prompts, vec!["Hi", "Bye"]);
+    assert!(log.closed);
+
+    // both exchanges are archived in one conversation
+    let messages =
+        conversation::list_messages(&session.db, &session.conversation.id, 10, 0).unwrap();
+    let rows: Vec<(&str, &str, Option<&str>, Option<&str>)> = messages
+        .iter()
+        .map(|m| {
+            (
+                m.prompt.as_str(),
+                m.response.as_str(),
+                m.model.as_deref(),
+                m.assistant.as_deref(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        rows,
+        vec![
+            ("Hi", "Hello there.", Some("mock"), Some("Morpha")),
+            ("Bye", "Goodbye.", Some("mock"), Some("Morpha")),
+        ]
+    );
+}
+
+#[test]
+fn test_session_non_interactive() {
+    let mock = Mock::new().reply("Four.");
+    let (mut session, log, status) = common::session(mock, Mode::NonInteractive);
+    let stdout = common::run(&mut session, "What is\ntwo plus two?\n");
+    assert_eq!(stdout, "Four.\n");
+    assert_eq!(status.text(), "");
+    assert_eq!(log.borrow().prompts, vec!["What is\ntwo plus two?"]);
+}
+
+#[test]
+fn test_session_extract_code() {
+    let reply = "Run this:\n\n```sh\ncargo build\n```\n\nthen:\n```\ncargo test\n```\n";
+    let mock = Mock::new().reply(reply);
+    let (mut session, _, _) = common::session(mock, Mode::NonInteractive);
+    session.extract_code = true;
+    let stdout = common::run(&mut session, "How do I build?\n");
+    assert_eq!(stdout, "cargo build\ncargo test\n");
+}
+
+#[test]
+fn test_session_code_commands() {
+    let mock = Mock::new().reply("Try:\n```rust\nfn main() {}\n```");
+    let (mut session, _, _) = common::session(mock, Mode::Interactive);
+    let stdout = common::run(&mut session, "Example?\n/code\n/code 1\n");
+    assert!(
+        stdout.ends_with("1. rust (1 line) fn main() {}\nfn main() {}\n"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_session_backend_error() {
+    let mock = Mock::new().fail("service unavailable").reply("Recovered.");
+    let (mut session, log, status) = common::session(mock, Mode::Interactive);
+    let stdout = common::run(&mut session, "first\nsecond\n");
+    assert!(stdout.ends_with("Recovered.\n"));
+    assert!(status.text().contains("service unavailable\n"));
+    assert_eq!(log.borrow().prompts, vec!["first", "second"]);
+
+    // only the answered prompt is archived
+    let messages =
+        conversation::list_messages(&session.db, &session.conversation.id, 10, 0).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].prompt, "second");
+}
+
+#[test]
+fn test_session_commands() {
+    let mock = Mock::new().reply("An answer.").reply("A follow up.");
+    let (mut session, log, status) = common::session(mock, Mode::Interactive);
+    let stdout = common::run(&mut session, "Question\n/cite 1\nFollow up\n/bogus\n/q\n");
+    assert!(
+        stdout.contains("citing in your next prompt: 1"),
+        "{}",
+        stdout
+    );
+    assert!(status.text().contains("unknown command"));
+
+    // the cited message is sent with the prompt and recorded in the archive
+    let log = log.borrow();
+    assert!(log.prompts[1].contains("<cited_message id=\"1\""));
+    assert!(log.prompts[1].ends_with("Follow up"));
+    assert_eq!(citation::cited_by(&session.db, 2).unwrap(), vec![1]);
+}
+
+#[test]
+fn test_session_failed_explanation() {
+    let mock = Mock::new()
+        .reply("An answer.")
+        .fail("service unavailable")
+        .reply("Unrelated.");
+    let (mut session, log, status) = common::session(mock, Mode::Interactive);
+    common::run(&mut session, "Question\n/explain 1\nSomething else\n");
+    assert!(status.text().contains("service unavailable\n"));
+
+    // the material of the failed explanation is not cited again
+    let log = log.borrow();
+    assert!(log.prompts[1].contains("<cited_message id=\"1\""));
+    assert_eq!(log.prompts[2], "Something else");
+    assert!(citation::cited_by(&session.db, 2).unwrap().is_empty());
+    let explanations: i64 = session
+        .db
+        .query_row("SELECT COUNT(*) FROM explanations", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(explanations, 0);
+}
+
+#[test]
+fn test_session_resume() {
+    let mock = Mock::new().reply("First answer.");
+    let (mut session, _, _) = common::session(mock, Mode::NonInteractive);
+    common::run(&mut session, "First question");
+    let id = session.conversation.id.clone();
+
+    let mock = Mock::new().reply("Second answer.");
+    let log = mock.log();
+    session.backend = Box::new(mock);
+    common::block_on(async {
+        session.start(Some(&id[..8])).await.unwrap();
+        session
+            .run(&mut "Second question".as_bytes(), &mut Vec::new())
+            .await
+            .unwrap();
+    });
+    assert_eq!(log.borrow().history, vec!["First question"]);
+    let conversation = conversation::read_conversation(&session.db, &id)
+        .unwrap()
+        .unwrap();
+    assert_eq!(conversation.messages.len(), 2);
+    assert_eq!(conversation.messages[1].response, "Second answer.");
+}
+
+#[test]
+fn test_session_persona() {
+    let dir = common::temp_dir("persona");
+    let reviewer =
+        "---\nname: Reviewer\nmodel: gpt-4o\ntemperature: 0.2\n---\nReview code tersely.\n";
+    std::fs::write(dir.join("reviewer.md"), reviewer).unwrap();
+    std::fs::write(dir.join("tutor.md"), "Teach patiently.\n").unwrap();
+
+    let mock = Mock::new().reply("Hello.").reply("Looks fine.");
+    let (mut session, log, _) = common::session(mock, Mode::Interactive);
+    session.personalities = Some(dir);
+    let input = "Hi\n/persona\n/persona reviewer\nReview this\n/persona translator\n";
+    let out = common::run(&mut session, input);
+    assert!(out.contains("  Reviewer (gpt-4o)\n  tutor\n"));
+    assert_eq!(log.borrow().personalities, vec!["Reviewer"]);
+    assert_eq!(
+        log.borrow().instructions.as_deref(),
+        Some("Review code tersely.\n")
+    );
+    assert_eq!(session.personality.name, "Reviewer");
+
+    // each message records the personality and model answering it
+    let messages = &session.conversation.messages;
+    assert_eq!(messages[0].assistant.as_deref(), Some("Morpha"));
+    assert_eq!(messages[0].model.as_deref(), Some("mock"));
+    assert_eq!(messages[1].assistant.as_deref(), Some("Reviewer"));
+    assert_eq!(messages[1].model.as_deref(), Some("gpt-4o"));
+}
+
+#[test]
+fn test_session_batch() {
+    let dir = common::temp_dir("batch_persona");
+    std::fs::write(
+        dir.join("reviewer.md"),
+        "---\nname: Reviewer\n---\nReview.\n",
+    )
+    .unwrap();
+
+    let mock = Mock::new()
+        .reply("Hello.")
+        .fail("overloaded")
+        .reply("Looks fine.");
+    let (mut session, log, status) = common::session(mock, Mode::NonInteractive);
+    session.personalities = Some(dir);
+    let script = r#"
+        {"id": "greet", "prompt": "Hi"}
+        {"prompt": "Are you there?"}
+        {"prompt": "Review this", "persona": "reviewer"}
+    "#;
+    let turns = batch::parse(script).unwrap();
+    let mut out = Vec::new();
+    let summary = common::block_on(async {
+        session.start(None).await.unwrap();
+        let summary = batch::run(&mut session, &turns, &mut out, true).await;
+        session.close().await.unwrap();
+        summary.unwrap()
+    });
+    assert_eq!(
+        summary,
+        batch::Summary {
+            turns: 3,
+            failed: 1
+        }
+    );
+
+    // a failed turn is reported and the rest of the script still runs
+    let results: Vec<serde_json::Value> = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0]["turn_id"], "greet");
+    assert_eq!(results[0]["response"], "Hello.");
+    assert!(results[1]["error"].as_str().unwrap().contains("overloaded"));
+    assert_eq!(results[2]["turn"], 3);
+    assert_eq!(results[2]["assistant"], "Reviewer");
+    assert!(status.text().contains("turn 2: "));
+    assert_eq!(log.borrow().prompts.len(), 3);
+    assert_eq!(session.conversation.messages.len(), 2);
+}
+
+#[test]
+fn test_session_interrupted_at_prompt() {
+    let (mut session, log, _) = common::session(Mock::new(), Mode::Interactive);
+    session.interrupt.trigger();
+    let result = common::block_on(async {
+        session.start(None).await.unwrap();
+        let result = session
+            .run(&mut "never sent\n".as_bytes(), &mut Vec::new())
+            .await;
+        session.close().await.unwrap();
+        result
+    });
+    assert!(matches!(result, Err(MorphaError::Interrupted)));
+    assert!(log.borrow().prompts.is_empty());
+    assert!(log.borrow().closed);
+}
+
+#[test]
+fn test_session_cancelled_response() {
+    let mock = Mock::new()
+        .reply("Answered.")
+        .hang()
+        .reply("Answered again.");
+    let (mut session, log, status) = common::session(mock, Mode::Interactive);
+    let interrupt = session.interrupt.clone();
+    let sent = Rc::clone(&log);
+    let mut out = Vec::new();
+    common::block_on(async {
+        session.start(None).await.unwrap();
+        // interrupt once the second prompt is waiting for its response
+        let cancel = async {
+            while sent.borrow().prompts.len() < 2 {
+                tokio::task::yield_now().await;
+            }
+            interrupt.trigger();
+        };
+        let mut input = "first\nsecond\nthird\n".as_bytes();
+        let (result, ()) = tokio::join!(session.run(&mut input, &mut out), cancel);
+        result.unwrap();
+        session.close().await.unwrap();
+    });
+    let stdout = String::from_utf8(out).unwrap();
+    assert!(stdout.ends_with("Answered.\nAnswered again.\n"));
+    assert!(status.text().contains("response cancelled\n"));
+    let log = log.borrow();
+    assert_eq!(log.prompts, vec!["first", "second", "third"]);
+    assert_eq!(log.cancelled, 1);
+    assert!(log.closed);
+
+    // the cancelled exchange is not archived
+    let messages =
+        conversation::list_messages(&session.db, &session.conversation.id, 10, 0).unwrap();
+    let prompts: Vec<&str> = messages.iter().map(|m| m.prompt.as_str()).collect();
+    assert_eq!(prompts, vec!["first", "third"]);
+}
+
+#[test]
+fn test_ctrl_c_ends_session() {
+    let server = common::StandIn::stalled();
+    let home = common::temp_dir("ctrl_c");
+    std::fs::write(home.join(".morpha_profile"), "You are a test.").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_morpha"))
+        .args(["--api-base", &server.api_base])
+        .env("HOME", &home)
+        .env("NO_PROXY", "127.0.0.1")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    child.stdin.take().unwrap().write_all(b"Hello\n").unwrap();
+
+    // the request is sent after the Ctrl-C handler is installed
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while server.requests().is_empty() {
+        assert!(Instant::now() < deadline, "no request received");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // a press before the session begins waiting on the response is ignored, so press until one
+    // cancels it
+    let status = loop {
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGINT) };
+        std::thread::sleep(Duration::from_millis(50));
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "not interrupted");
+    };
+    assert_eq!(status.code(), Some(130));
+    assert_eq!(server.requests().len(), 1);
+}